@@ -0,0 +1,127 @@
+use std::f32::consts::TAU;
+
+use crate::scheduler::FRAME_RATE;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+pub const DEFAULT_FREQUENCY: f32 = 356.0;
+pub const DEFAULT_VOLUME: f32 = 0.1;
+
+const RAMP_SECONDS: f32 = 0.002;     // fade in and out this fast to avoid clicks
+const MAX_BUFFERED_SECONDS: f32 = 1.0; // drop the oldest samples if nobody drains them
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+impl Waveform {
+    pub fn parse(s: &str) -> Option<Waveform> {
+        match s {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+}
+
+// turns the beeper state into mono PCM, one emulated frame at a time. changes of state are
+// placed at their position within the frame, so beeps start and stop on time
+pub struct AudioGenerator {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    pub volume: f32,             // 0 to 1
+    sample_rate: u32,
+    samples: Vec<f32>,           // generated and not yet taken, in -1 to 1
+    events: Vec<(f32, bool)>,    // beeper changes in the current frame, as (position 0..1, on)
+    on: bool,                    // beeper state at the start of the current frame
+    phase: f32,                  // position in the wave's period, 0..1
+    gain: f32,                   // envelope, 0..1
+    sample_debt: f64,            // fractional samples carried between frames
+}
+
+impl Default for AudioGenerator {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl AudioGenerator {
+    pub fn new(sample_rate: u32) -> AudioGenerator {
+        AudioGenerator {
+            waveform: Waveform::Square,
+            frequency: DEFAULT_FREQUENCY,
+            volume: DEFAULT_VOLUME,
+            sample_rate,
+            samples: Vec::new(),
+            events: Vec::new(),
+            on: false,
+            phase: 0.0,
+            gain: 0.0,
+            sample_debt: 0.0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // `position` is how far into the current frame the change happened, from 0 to 1
+    pub fn set_beep(&mut self, on: bool, position: f32) {
+        self.events.push((position.clamp(0.0, 1.0), on));
+    }
+
+    // generates the samples for the frame that just finished
+    pub fn end_frame(&mut self) {
+        self.sample_debt += self.sample_rate as f64 / FRAME_RATE as f64;
+        let count = self.sample_debt as usize;
+        self.sample_debt -= count as f64;
+
+        let ramp_step = 1.0 / (RAMP_SECONDS * self.sample_rate as f32).max(1.0);
+        let phase_step = self.frequency / self.sample_rate as f32;
+        let mut events = self.events.drain(..).peekable();
+
+        for i in 0..count {
+            let position = i as f32 / count as f32;
+            while let Some((_, on)) = events.next_if(|(at, _)| *at <= position) {
+                self.on = on;
+            }
+
+            let target = if self.on { 1.0 } else { 0.0 };
+            if self.gain < target {
+                self.gain = (self.gain + ramp_step).min(target);
+            } else {
+                self.gain = (self.gain - ramp_step).max(target);
+            }
+
+            let wave = match self.waveform {
+                Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Sine => (self.phase * TAU).sin(),
+            };
+            self.samples.push(wave * self.gain * self.volume);
+            self.phase = (self.phase + phase_step).fract();
+        }
+
+        // changes right at the end of the frame apply from the next one
+        for (_, on) in events {
+            self.on = on;
+        }
+
+        let max = (MAX_BUFFERED_SECONDS * self.sample_rate as f32) as usize;
+        if self.samples.len() > max {
+            self.samples.drain(..self.samples.len() - max);
+        }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::disasm;
+use crate::memory::{Memory, LAST_INSTR_ADDR};
+use crate::quirks::Quirks;
+
+// how control leaves a basic block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+    Fallthrough(u16),                  // runs into the next block, which something else jumps to
+    Jump(u16),                         // 1NNN
+    Call { target: u16, ret: u16 },    // 2NNN, continuing at `ret` once the subroutine returns
+    Skip { next: u16, skipped: u16 },  // 3XNN, 4XNN, 5XY0, 9XY0, EX9E and EXA1
+    Return,                            // 00EE
+    Computed(u16),                     // BNNN, only its base address is known
+    End,                               // runs off the end of memory
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Call,
+    Return,  // from a call to the instruction after it
+    Skip,    // the instruction after a skip is stepped over
+    NoSkip,  // the skip isn't taken
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Call => "call",
+            EdgeKind::Return => "return",
+            EdgeKind::Skip => "skip",
+            EdgeKind::NoSkip => "no skip",
+        }
+    }
+}
+
+// straight-line run of instructions, only ever entered at the top
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub instructions: Vec<(u16, u16)>, // (address, opcode)
+    pub exit: Exit,
+}
+
+impl Block {
+    // address just past the last instruction
+    pub fn end(&self) -> u16 {
+        self.start + 2 * self.instructions.len() as u16
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+
+    // known successors. a computed jump has none, see `Exit::Computed`
+    pub fn successors(&self) -> Vec<(u16, EdgeKind)> {
+        match self.exit {
+            Exit::Fallthrough(next) => vec![(next, EdgeKind::Fallthrough)],
+            Exit::Jump(target) => vec![(target, EdgeKind::Jump)],
+            Exit::Call { target, ret } => vec![(target, EdgeKind::Call), (ret, EdgeKind::Return)],
+            Exit::Skip { next, skipped } => vec![(next, EdgeKind::NoSkip), (skipped, EdgeKind::Skip)],
+            Exit::Return | Exit::Computed(_) | Exit::End => Vec::new(),
+        }
+    }
+}
+
+enum Flow {
+    Next,
+    Jump(u16),
+    Call(u16),
+    Skip,
+    Return,
+    Computed(u16),
+}
+
+fn flow(instr: u16) -> Flow {
+    let nnn = instr & 0x0FFF;
+    match instr & 0xF000 {
+        0x0000 if instr == 0x00EE => Flow::Return,
+        0x1000 => Flow::Jump(nnn),
+        0x2000 => Flow::Call(nnn),
+        0x3000 | 0x4000 => Flow::Skip,
+        0x5000 | 0x9000 if instr & 0x000F == 0 => Flow::Skip,
+        0xB000 => Flow::Computed(nnn),
+        0xE000 if instr & 0x00FF == 0x9E || instr & 0x00FF == 0xA1 => Flow::Skip,
+        _ => Flow::Next,
+    }
+}
+
+// control flow graph of everything reachable from the entry points without running
+// anything. data the program jumps over is left out, code only reached through BNNN too
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,              // by start address
+    pub functions: BTreeMap<u16, BTreeSet<u16>>,   // entry points and subroutines, with the blocks in each
+}
+
+impl Cfg {
+    pub fn build(memory: &Memory, entries: &[u16]) -> Cfg {
+        // first find every reachable instruction, and where blocks have to start
+        let mut leaders: BTreeSet<u16> = entries.iter().copied().collect();
+        let mut reachable = BTreeSet::new();
+        let mut work: Vec<u16> = entries.to_vec();
+        let mut subroutines: BTreeSet<u16> = entries.iter().copied().collect();
+
+        while let Some(mut pc) = work.pop() {
+            while pc <= LAST_INSTR_ADDR && reachable.insert(pc) {
+                let targets = match flow(memory.get_instr(pc)) {
+                    Flow::Next => {
+                        pc += 2;
+                        continue;
+                    }
+                    Flow::Jump(target) => vec![target],
+                    Flow::Call(target) => {
+                        subroutines.insert(target);
+                        vec![target, pc + 2]
+                    }
+                    Flow::Skip => vec![pc + 2, pc + 4],
+                    Flow::Return | Flow::Computed(_) => Vec::new(),
+                };
+                leaders.extend(&targets);
+                work.extend(targets);
+                break;
+            }
+        }
+
+        let mut cfg = Cfg::default();
+        for &start in leaders.iter().filter(|addr| reachable.contains(addr)) {
+            let block = Self::block_from(memory, start, &leaders);
+            cfg.blocks.insert(start, block);
+        }
+        for entry in subroutines.into_iter().filter(|entry| cfg.blocks.contains_key(entry)) {
+            let blocks = cfg.function_blocks(entry);
+            cfg.functions.insert(entry, blocks);
+        }
+        cfg
+    }
+
+    fn block_from(memory: &Memory, start: u16, leaders: &BTreeSet<u16>) -> Block {
+        let mut instructions = Vec::new();
+        let mut pc = start;
+        let exit = loop {
+            if pc > LAST_INSTR_ADDR {
+                break Exit::End;
+            }
+            let instr = memory.get_instr(pc);
+            instructions.push((pc, instr));
+            match flow(instr) {
+                Flow::Next if leaders.contains(&(pc + 2)) => break Exit::Fallthrough(pc + 2),
+                Flow::Next => pc += 2,
+                Flow::Jump(target) => break Exit::Jump(target),
+                Flow::Call(target) => break Exit::Call { target, ret: pc + 2 },
+                Flow::Skip => break Exit::Skip { next: pc + 2, skipped: pc + 4 },
+                Flow::Return => break Exit::Return,
+                Flow::Computed(base) => break Exit::Computed(base),
+            }
+        };
+        Block { start, instructions, exit }
+    }
+
+    // blocks reachable from `entry` without following calls, i.e. up to its returns
+    fn function_blocks(&self, entry: u16) -> BTreeSet<u16> {
+        let mut blocks = BTreeSet::new();
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            let block = match self.blocks.get(&start) {
+                Some(block) if blocks.insert(start) => block,
+                _ => continue,
+            };
+            for (next, kind) in block.successors() {
+                if kind != EdgeKind::Call {
+                    work.push(next);
+                }
+            }
+        }
+        blocks
+    }
+
+    pub fn block_containing(&self, addr: u16) -> Option<&Block> {
+        self.blocks.range(..=addr).next_back().map(|(_, block)| block).filter(|block| block.contains(addr))
+    }
+
+    // the subroutines `function` calls, with whether it also makes computed jumps
+    pub fn callees(&self, function: u16) -> (BTreeSet<u16>, bool) {
+        let mut callees = BTreeSet::new();
+        let mut computed = false;
+        for block in self.functions.get(&function).into_iter().flatten().filter_map(|start| self.blocks.get(start)) {
+            match block.exit {
+                Exit::Call { target, .. } => {
+                    callees.insert(target);
+                }
+                Exit::Computed(_) => computed = true,
+                _ => (),
+            }
+        }
+        (callees, computed)
+    }
+
+    // Graphviz source with a node per block, listing its instructions.
+    // computed jumps lead to a separate "unresolved" node, labelled with the register `quirks` adds
+    pub fn to_dot(&self, quirks: &Quirks) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        let mut unresolved = false;
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for (addr, instr) in &block.instructions {
+                let _ = write!(label, "{:03x}  {}\\l", addr, disasm::disassemble(*instr));
+            }
+            let _ = writeln!(dot, "    b{:03x} [label=\"{}\"];", block.start, label);
+
+            for (next, kind) in block.successors() {
+                let style = match kind {
+                    EdgeKind::Call => ", style=dashed",
+                    EdgeKind::Return => ", style=dotted",
+                    _ => "",
+                };
+                let _ = writeln!(dot, "    b{:03x} -> b{:03x} [label=\"{}\"{}];", block.start, next, kind.name(), style);
+            }
+            if let Exit::Computed(base) = block.exit {
+                unresolved = true;
+                let _ = writeln!(dot, "    b{:03x} -> unresolved [label=\"{}\", style=dashed, color=red];", block.start, disasm::computed_jump(base, quirks));
+            }
+        }
+
+        if unresolved {
+            dot.push_str("    unresolved [shape=octagon, color=red, label=\"computed jump\"];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // Graphviz source with a node per subroutine and an edge per call
+    pub fn call_graph_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [fontname=\"monospace\"];\n");
+        let mut unresolved = false;
+
+        for &function in self.functions.keys() {
+            let _ = writeln!(dot, "    f{:03x} [label=\"{:#05x}\"];", function, function);
+            let (callees, computed) = self.callees(function);
+            for callee in callees {
+                let _ = writeln!(dot, "    f{:03x} -> f{:03x};", function, callee);
+            }
+            if computed {
+                unresolved = true;
+                let _ = writeln!(dot, "    f{:03x} -> unresolved [style=dashed, color=red];", function);
+            }
+        }
+
+        if unresolved {
+            dot.push_str("    unresolved [shape=octagon, color=red, label=\"computed jump\"];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,74 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::palette::Palette;
+
+// user settings from `$XDG_CONFIG_HOME/rchip/config` (usually ~/.config/rchip/config),
+// as lines of `key = value`. command line options take precedence
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub palette: Option<Palette>,
+    pub fullscreen_size: Option<(usize, usize)>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("rchip").join("config"))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(format!("line {}: expected `key = value`", i + 1)),
+            };
+
+            match key {
+                "palette" => {
+                    config.palette = Some(Palette::parse(value)
+                        .ok_or_else(|| format!("line {}: unknown palette {}", i + 1, value))?);
+                }
+                "fullscreen_size" => {
+                    config.fullscreen_size = Some(parse_size(value)
+                        .ok_or_else(|| format!("line {}: expected a size such as 2560x1440, got {}", i + 1, value))?);
+                }
+                _ => return Err(format!("line {}: unknown setting {}", i + 1, key)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // a missing file just means the defaults
+    pub fn load() -> Result<Config, String> {
+        let path = match Config::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Config::default()),
+        };
+
+        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// e.g. "1920x1080"
+pub fn parse_size(text: &str) -> Option<(usize, usize)> {
+    let (width, height) = text.split_once('x')?;
+    let size = (width.trim().parse().ok()?, height.trim().parse().ok()?);
+    if size.0 == 0 || size.1 == 0 {
+        return None;
+    }
+    Some(size)
+}
@@ -0,0 +1,198 @@
+use std::fmt::Write;
+
+use crate::cfg::Cfg;
+use crate::disasm;
+use crate::memory::{Memory, ADDR_MASK, MEM_SIZE};
+use crate::quirks::Quirks;
+use crate::vm::{VM, START_ADDR};
+
+// counts, per address, how often the program executed, read and wrote it. fetching an
+// instruction only counts as executing it, reads and writes are the ones made through I
+pub struct Coverage {
+    pub executed: Vec<u32>,
+    pub read: Vec<u32>,
+    pub written: Vec<u32>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            executed: vec![0; MEM_SIZE],
+            read: vec![0; MEM_SIZE],
+            written: vec![0; MEM_SIZE],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.executed.fill(0);
+        self.read.fill(0);
+        self.written.fill(0);
+    }
+
+    // the instruction at PC is about to run
+    pub fn record(&mut self, vm: &VM) {
+        let pc = vm.pc as usize;
+        if pc + 1 >= MEM_SIZE {
+            return;
+        }
+        self.executed[pc] = self.executed[pc].saturating_add(1);
+
+        let opcode = vm.memory.get_instr(vm.pc);
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let i = vm.ir as usize;
+        match opcode & 0xF000 {
+            0xD000 => Self::count(&mut self.read, i, (opcode & 0x000F) as usize),
+            0xF000 => match opcode & 0x00FF {
+                0x33 => Self::count(&mut self.written, i, 3),
+                0x55 => Self::count(&mut self.written, i, x + 1),
+                0x65 => Self::count(&mut self.read, i, x + 1),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    // accesses wrap around like the VM's
+    fn count(counts: &mut [u32], start: usize, len: usize) {
+        for addr in start..start + len {
+            let count = &mut counts[addr & ADDR_MASK as usize];
+            *count = count.saturating_add(1);
+        }
+    }
+
+    // the code in `memory`: everything reachable from 0x200, plus whatever actually ran
+    // that the analysis couldn't find, e.g. behind a computed jump
+    pub fn code(&self, memory: &Memory) -> Cfg {
+        let mut entries = vec![START_ADDR as u16];
+        let cfg = Cfg::build(memory, &entries);
+        entries.extend(self.executed_addrs().filter(|addr| cfg.block_containing(*addr).is_none()));
+        if entries.len() == 1 {
+            return cfg;
+        }
+        Cfg::build(memory, &entries)
+    }
+
+    fn executed_addrs(&self) -> impl Iterator<Item = u16> + '_ {
+        self.executed.iter().enumerate().filter(|(_, hits)| **hits > 0).map(|(addr, _)| addr as u16)
+    }
+
+    fn instructions(cfg: &Cfg) -> impl Iterator<Item = &(u16, u16)> {
+        cfg.blocks.values().flat_map(|block| block.instructions.iter())
+    }
+
+    // e.g. "187 of 203 instructions executed (92.1%)"
+    pub fn summary(&self, memory: &Memory) -> String {
+        let cfg = self.code(memory);
+        let (total, executed) = self.tally(Self::instructions(&cfg));
+        format!("{} of {} instructions executed ({:.1}%)", executed, total, percent(executed, total))
+    }
+
+    // (instructions, how many of them ran)
+    fn tally<'a>(&self, instructions: impl Iterator<Item = &'a (u16, u16)>) -> (usize, usize) {
+        instructions.fold((0, 0), |(total, executed), (addr, _)| {
+            (total + 1, executed + (self.executed[*addr as usize] > 0) as usize)
+        })
+    }
+
+    fn tally_function(&self, cfg: &Cfg, entry: u16) -> (usize, usize) {
+        let blocks = cfg.functions[&entry].iter().filter_map(|start| cfg.blocks.get(start));
+        self.tally(blocks.flat_map(|block| block.instructions.iter()))
+    }
+
+    // the disassembly with how often each instruction ran in front of it, or ##### for
+    // code that never did, followed by the memory the program read and wrote
+    pub fn listing(&self, memory: &Memory, quirks: &Quirks) -> String {
+        let cfg = self.code(memory);
+        let (total, executed) = self.tally(Self::instructions(&cfg));
+        let mut listing = String::new();
+        let _ = writeln!(listing, "; {} of {} instructions executed ({:.1}%), {} never ran", executed, total, percent(executed, total), total - executed);
+
+        for block in cfg.blocks.values() {
+            if cfg.functions.contains_key(&block.start) {
+                listing.push('\n');
+                let (total, executed) = self.tally_function(&cfg, block.start);
+                let _ = writeln!(listing, "; function {:03x}, {} of {} instructions executed", block.start, executed, total);
+            }
+            let _ = writeln!(listing, "{:03x}:", block.start);
+            let lines = disasm::disassemble_block(block, quirks);
+            for (n, line) in lines.iter().enumerate() {
+                let hits = match block.instructions.get(n) {
+                    Some((addr, _)) if self.executed[*addr as usize] == 0 => String::from("#####"),
+                    Some((addr, _)) => self.executed[*addr as usize].to_string(),
+                    None => String::new(),
+                };
+                let _ = writeln!(listing, "{:>9}{}", hits, line);
+            }
+        }
+
+        for (name, counts) in [("read", &self.read), ("written", &self.written)] {
+            listing.push('\n');
+            let ranges = ranges(counts);
+            if ranges.is_empty() {
+                let _ = writeln!(listing, "; nothing {}", name);
+            }
+            for (start, end, hits) in ranges {
+                let _ = writeln!(listing, "; {} {:03x}-{:03x}, {} times", name, start, end, hits);
+            }
+        }
+        listing
+    }
+
+    // everything `listing` has, for other tools to read
+    pub fn to_json(&self, memory: &Memory) -> String {
+        let cfg = self.code(memory);
+        let (total, executed) = self.tally(Self::instructions(&cfg));
+        let mut json = format!("{{\n  \"instructions\": {},\n  \"executed\": {},\n", total, executed);
+
+        let code: Vec<String> = Self::instructions(&cfg)
+            .map(|(addr, opcode)| format!("{{\"addr\": {}, \"opcode\": {}, \"hits\": {}}}", addr, opcode, self.executed[*addr as usize]))
+            .collect();
+        let _ = writeln!(json, "  \"code\": [\n    {}\n  ],", code.join(",\n    "));
+
+        let functions: Vec<String> = cfg.functions.keys()
+            .map(|entry| {
+                let (total, executed) = self.tally_function(&cfg, *entry);
+                format!("{{\"addr\": {}, \"instructions\": {}, \"executed\": {}}}", entry, total, executed)
+            })
+            .collect();
+        let _ = writeln!(json, "  \"functions\": [\n    {}\n  ],", functions.join(",\n    "));
+
+        // [address, count] for every byte accessed at least once
+        let accessed = |counts: &[u32]| -> String {
+            let pairs: Vec<String> = counts.iter().enumerate()
+                .filter(|(_, hits)| **hits > 0)
+                .map(|(addr, hits)| format!("[{}, {}]", addr, hits))
+                .collect();
+            pairs.join(", ")
+        };
+        let _ = writeln!(json, "  \"read\": [{}],", accessed(&self.read));
+        let _ = writeln!(json, "  \"written\": [{}]", accessed(&self.written));
+        json.push_str("}\n");
+        json
+    }
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * part as f64 / total as f64 }
+}
+
+// runs of consecutive addresses accessed at least once, as (first, last, total accesses)
+fn ranges(counts: &[u32]) -> Vec<(usize, usize, u64)> {
+    let mut ranges: Vec<(usize, usize, u64)> = Vec::new();
+    for (addr, &count) in counts.iter().enumerate().filter(|(_, hits)| **hits > 0) {
+        match ranges.last_mut() {
+            Some((_, end, hits)) if *end + 1 == addr => {
+                *end = addr;
+                *hits += count as u64;
+            }
+            _ => ranges.push((addr, addr, count as u64)),
+        }
+    }
+    ranges
+}
@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::cfg::Cfg;
+use crate::error::VmError;
+use crate::memory::Memory;
+use crate::vm::{MAX_ROM_SIZE, START_ADDR};
+
+const BYTES_PER_LINE: usize = 8;
+const NAME_KINDS: [&str; 3] = ["sub", "loc", "data"]; // a label referred to in more than one way gets the first
+
+#[derive(Clone, Copy, PartialEq)]
+enum Item {
+    Instr(u16),
+    Byte(u8),
+}
+
+// everything between `start` and `end` is part of it, but only what's in `bodies` is
+// free for other structures. the rest are the instructions it stands for
+struct Structure {
+    start: u16,
+    end: u16,
+    bodies: Vec<(u16, u16)>,
+    kind: Kind,
+}
+
+// what an instruction that's part of a structure is written as
+enum Token<'a> {
+    Begin(&'a str), // `if cond begin`, standing for the skip
+    Skip,           // the jump `begin` stands for
+    Else,
+    Again,
+}
+
+enum Kind {
+    If { cond: String, else_at: Option<u16> }, // `if cond begin`, with the jump `else` stands for
+    Loop { again_at: u16 },
+}
+
+impl Structure {
+    fn fits(&self, other: &Structure) -> bool {
+        let inside = |inner: &Structure, outer: &Structure| {
+            outer.bodies.iter().any(|(start, end)| inner.start >= *start && inner.end <= *end)
+        };
+        self.end <= other.start || other.end <= self.start || inside(self, other) || inside(other, self)
+    }
+}
+
+fn reg(r: u16) -> String {
+    format!("v{:x}", r)
+}
+
+// the condition `if ... then` needs so the next instruction runs exactly when `instr`
+// doesn't skip it, or with `negated`, the one `if ... begin` needs to compile to `instr`
+fn condition(instr: u16, negated: bool) -> Option<String> {
+    let x = reg((instr & 0x0F00) >> 8);
+    let y = reg((instr & 0x00F0) >> 4);
+    let nn = instr & 0x00FF;
+    let (equal, rhs) = match instr & 0xF000 {
+        0x3000 => (false, format!("{:#04x}", nn)),
+        0x4000 => (true, format!("{:#04x}", nn)),
+        0x5000 if instr & 0x000F == 0 => (false, y),
+        0x9000 if instr & 0x000F == 0 => (true, y),
+        0xE000 if nn == 0x9E => return Some(format!("{} {}", x, if negated { "key" } else { "-key" })),
+        0xE000 if nn == 0xA1 => return Some(format!("{} {}", x, if negated { "-key" } else { "key" })),
+        _ => return None,
+    };
+    let op = if equal != negated { "==" } else { "!=" };
+    Some(format!("{} {} {}", x, op, rhs))
+}
+
+// Octo source for `rom` that assembles back to exactly the same bytes. code reachable from
+// 0x200 becomes instructions, with subroutines and jump targets labelled and skips turned
+// into `if`, and everything else is left as bytes. fails if `rom` doesn't fit in memory
+pub fn decompile(rom: &[u8]) -> Result<String, VmError> {
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(VmError::RomTooLarge(rom.len()));
+    }
+
+    let start = START_ADDR as u16;
+    let end = start + rom.len() as u16;
+
+    let mut memory = Memory::new();
+    memory.map_range(START_ADDR, rom.len(), rom);
+    let cfg = Cfg::build(&memory, &[start]);
+    let code: BTreeSet<u16> = cfg.blocks.values().flat_map(|block| block.instructions.iter().map(|(addr, _)| *addr)).collect();
+
+    // instructions where the analysis found them, unless they overlap one before
+    let mut items = BTreeMap::new();
+    let mut addr = start;
+    while addr < end {
+        if code.contains(&addr) && addr + 1 < end {
+            items.insert(addr, Item::Instr(memory.get_instr(addr)));
+            addr += 2;
+        } else {
+            items.insert(addr, Item::Byte(memory.get(addr)));
+            addr += 1;
+        }
+    }
+    let boundary = |addr: u16| items.contains_key(&addr) || addr == end;
+    let instr_at = |addr: u16| match items.get(&addr) {
+        Some(Item::Instr(instr)) => Some(*instr),
+        _ => None,
+    };
+    let forward_jump = |addr: u16| instr_at(addr).filter(|instr| instr & 0xF000 == 0x1000).map(|instr| instr & 0x0FFF);
+
+    let mut referenced = BTreeSet::new();
+    for item in items.values() {
+        if let Item::Instr(instr) = item {
+            if matches!(instr & 0xF000, 0x1000 | 0x2000 | 0xA000 | 0xB000) {
+                referenced.insert(instr & 0x0FFF);
+            }
+        }
+    }
+
+    // candidates for structures, each site's preferred form first
+    let mut candidates: Vec<Structure> = Vec::new();
+    for (&addr, item) in &items {
+        let instr = match item {
+            Item::Instr(instr) => *instr,
+            Item::Byte(_) => continue,
+        };
+
+        if instr & 0xF000 == 0x1000 && instr & 0x0FFF <= addr && instr & 0x0FFF >= start && boundary(instr & 0x0FFF) {
+            let top = instr & 0x0FFF;
+            candidates.push(Structure { start: top, end: addr + 2, bodies: vec![(top, addr)], kind: Kind::Loop { again_at: addr } });
+        }
+
+        // a skip over a forward jump is what `if ... begin` compiles to. nothing else
+        // may jump to the jump, there's no way to label it
+        let (cond, target) = match (condition(instr, true), forward_jump(addr + 2)) {
+            (Some(cond), Some(target)) if target > addr + 4 && target <= end && boundary(target) && !referenced.contains(&(addr + 2)) => (cond, target),
+            _ => continue,
+        };
+        if let Some(exit) = forward_jump(target - 2).filter(|exit| target - 2 >= addr + 4 && *exit > target && *exit <= end && boundary(*exit)) {
+            candidates.push(Structure {
+                start: addr,
+                end: exit,
+                bodies: vec![(addr + 4, target - 2), (target, exit)],
+                kind: Kind::If { cond: cond.clone(), else_at: Some(target - 2) },
+            });
+        }
+        candidates.push(Structure { start: addr, end: target, bodies: vec![(addr + 4, target)], kind: Kind::If { cond, else_at: None } });
+    }
+
+    // outermost first, keeping whatever nests properly with what's been kept so far
+    candidates.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+    let mut structures: Vec<Structure> = Vec::new();
+    let mut sites = BTreeSet::new();
+    for candidate in candidates {
+        let site = match candidate.kind {
+            Kind::If { .. } => candidate.start,
+            Kind::Loop { again_at } => again_at,
+        };
+        if !sites.contains(&site) && structures.iter().all(|s| s.fits(&candidate)) {
+            sites.insert(site);
+            structures.push(candidate);
+        }
+    }
+
+    let mut opens: BTreeMap<u16, usize> = BTreeMap::new();   // loops starting here
+    let mut closes: BTreeMap<u16, usize> = BTreeMap::new();  // ifs ending here
+    let mut tokens = BTreeMap::new();
+    for s in &structures {
+        match &s.kind {
+            Kind::If { cond, else_at } => {
+                tokens.insert(s.start, Token::Begin(cond));
+                tokens.insert(s.start + 2, Token::Skip);
+                if let Some(at) = else_at {
+                    tokens.insert(*at, Token::Else);
+                }
+                *closes.entry(s.end).or_default() += 1;
+            }
+            Kind::Loop { again_at } => {
+                *opens.entry(s.start).or_default() += 1;
+                tokens.insert(*again_at, Token::Again);
+            }
+        }
+    }
+
+    // only what the remaining instructions refer to needs a name, the most telling one
+    let mut kinds = BTreeMap::new();
+    for (&addr, item) in &items {
+        if let (Item::Instr(instr), None) = (item, tokens.get(&addr)) {
+            let target = instr & 0x0FFF;
+            let kind = match instr & 0xF000 {
+                0x2000 => "sub",
+                0x1000 | 0xB000 => "loc",
+                0xA000 => "data",
+                _ => continue,
+            };
+            if target < end && items.contains_key(&target) {
+                let best = kinds.entry(target).or_insert(kind);
+                if NAME_KINDS.iter().position(|k| *k == kind) < NAME_KINDS.iter().position(|k| k == best) {
+                    *best = kind;
+                }
+            }
+        }
+    }
+    let mut names: BTreeMap<u16, String> = kinds.iter().map(|(addr, kind)| (*addr, format!("{}_{:03x}", kind, addr))).collect();
+    names.insert(start, String::from("main"));
+
+    let mut out = String::new();
+    let mut depth = 1;
+    let mut bytes: Vec<u8> = Vec::new();
+    let line = |out: &mut String, depth: usize, text: &str| {
+        let _ = writeln!(out, "{}{}", "  ".repeat(depth), text);
+    };
+
+    for addr in start..=end {
+        let item = items.get(&addr);
+        let name = names.get(&addr);
+        let ends = closes.get(&addr).copied().unwrap_or(0);
+        let loops = opens.get(&addr).copied().unwrap_or(0);
+
+        let more_bytes = matches!(item, Some(Item::Byte(_))) && name.is_none() && ends == 0 && loops == 0;
+        if !more_bytes || bytes.len() == BYTES_PER_LINE {
+            for chunk in bytes.chunks(BYTES_PER_LINE) {
+                let chunk: Vec<String> = chunk.iter().map(|b| format!("{:#04x}", b)).collect();
+                line(&mut out, depth, &chunk.join(" "));
+            }
+            bytes.clear();
+        }
+
+        for _ in 0..ends {
+            depth -= 1;
+            line(&mut out, depth, "end");
+        }
+        if let Some(name) = name {
+            if !name.starts_with("loc") && !out.is_empty() {
+                out.push('\n');
+            }
+            let _ = writeln!(out, ": {}", name);
+        }
+        for _ in 0..loops {
+            line(&mut out, depth, "loop");
+            depth += 1;
+        }
+
+        let instr = match item {
+            Some(Item::Instr(instr)) => *instr,
+            Some(Item::Byte(byte)) => {
+                bytes.push(*byte);
+                continue;
+            }
+            None => continue,
+        };
+        match tokens.get(&addr) {
+            Some(Token::Begin(cond)) => {
+                line(&mut out, depth, &format!("if {} begin", cond));
+                depth += 1;
+            }
+            Some(Token::Skip) => (),
+            Some(Token::Else) => line(&mut out, depth - 1, "else"),
+            Some(Token::Again) => {
+                depth -= 1;
+                line(&mut out, depth, "again");
+            }
+            None => line(&mut out, depth, &statement(instr, &names)),
+        }
+    }
+
+    Ok(out)
+}
+
+fn target(nnn: u16, names: &BTreeMap<u16, String>) -> Option<String> {
+    names.get(&nnn).cloned()
+}
+
+fn raw(instr: u16) -> String {
+    format!("{:#04x} {:#04x}", instr >> 8, instr & 0xFF)
+}
+
+// a single Octo statement for `instr`, or its bytes when Octo has no way to write it
+fn statement(instr: u16, names: &BTreeMap<u16, String>) -> String {
+    let x = reg((instr & 0x0F00) >> 8);
+    let y = reg((instr & 0x00F0) >> 4);
+    let n = instr & 0x000F;
+    let nn = instr & 0x00FF;
+    let nnn = instr & 0x0FFF;
+
+    match instr & 0xF000 {
+        0x0000 => match instr {
+            0x00E0 => String::from("clear"),
+            0x00EE => String::from("return"),
+            _ => raw(instr),
+        },
+        // calls and jumps go by name, anywhere without one is written as bytes
+        0x1000 => target(nnn, names).map_or_else(|| format!("{}  # jump {:#05x}", raw(instr), nnn), |name| format!("jump {}", name)),
+        0x2000 => target(nnn, names).unwrap_or_else(|| format!("{}  # call {:#05x}", raw(instr), nnn)),
+        0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xE000 => match condition(instr, false) {
+            Some(cond) => format!("if {} then", cond),
+            None => raw(instr),
+        },
+        0x6000 => format!("{} := {:#04x}", x, nn),
+        0x7000 => format!("{} += {:#04x}", x, nn),
+        0x8000 => {
+            let op = match n {
+                0x0 => ":=",
+                0x1 => "|=",
+                0x2 => "&=",
+                0x3 => "^=",
+                0x4 => "+=",
+                0x5 => "-=",
+                0x6 => ">>=",
+                0x7 => "=-",
+                0xE => "<<=",
+                _ => return raw(instr),
+            };
+            format!("{} {} {}", x, op, y)
+        }
+        0xA000 => format!("i := {}", target(nnn, names).unwrap_or_else(|| format!("{:#05x}", nnn))),
+        0xB000 => target(nnn, names).map_or_else(|| format!("{}  # jump0 {:#05x}", raw(instr), nnn), |name| format!("jump0 {}", name)),
+        0xC000 => format!("{} := random {:#04x}", x, nn),
+        0xD000 => format!("sprite {} {} {:#x}", x, y, n),
+        0xF000 => match nn {
+            0x07 => format!("{} := delay", x),
+            0x0A => format!("{} := key", x),
+            0x15 => format!("delay := {}", x),
+            0x18 => format!("buzzer := {}", x),
+            0x1E => format!("i += {}", x),
+            0x29 => format!("i := hex {}", x),
+            0x33 => format!("bcd {}", x),
+            0x55 => format!("save {}", x),
+            0x65 => format!("load {}", x),
+            _ => raw(instr),
+        },
+        _ => raw(instr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use super::{decompile, VmError, MAX_ROM_SIZE};
+
+    // just enough Octo to assemble what `decompile` writes
+    fn assemble(source: &str) -> Vec<u8> {
+        let tokens: Vec<&str> = source.lines()
+            .flat_map(|line| line.split('#').next().unwrap_or("").split_whitespace())
+            .collect();
+        let mut labels: HashMap<&str, u16> = HashMap::new();
+        let mut out = Vec::new();
+        // the first pass finds where the labels are, the second uses them
+        for _ in 0..2 {
+            out = assemble_pass(&tokens, &mut labels);
+        }
+        out
+    }
+
+    fn assemble_pass<'a>(tokens: &[&'a str], labels: &mut HashMap<&'a str, u16>) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        let mut pending: Vec<usize> = Vec::new(); // jumps `end` and `else` fill in
+        let mut loops: Vec<u16> = Vec::new();
+        let pc = |out: &Vec<u8>| 0x200 + out.len() as u16;
+        let emit = |out: &mut Vec<u8>, instr: u16| out.extend([(instr >> 8) as u8, instr as u8]);
+        let patch = |out: &mut Vec<u8>, at: usize| {
+            let instr = 0x1000 | pc(out);
+            out[at] = (instr >> 8) as u8;
+            out[at + 1] = instr as u8;
+        };
+        let num = |token: &str| -> u16 {
+            match token.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16).unwrap(),
+                None => token.parse().unwrap(),
+            }
+        };
+        let reg = |token: &str| -> u16 {
+            assert!(token.len() == 2 && token.starts_with('v'), "not a register: {}", token);
+            u16::from_str_radix(&token[1..], 16).unwrap()
+        };
+        let addr = |token: &str, labels: &HashMap<&str, u16>| -> u16 {
+            labels.get(token).copied().unwrap_or_else(|| if token.starts_with("0x") { num(token) } else { 0 })
+        };
+
+        let mut tokens = tokens.iter().copied();
+        while let Some(token) = tokens.next() {
+            let mut next = || tokens.next().unwrap();
+            match token {
+                ":" => {
+                    let name = next();
+                    labels.insert(name, pc(&out));
+                }
+                "loop" => loops.push(pc(&out)),
+                "again" => emit(&mut out, 0x1000 | loops.pop().unwrap()),
+                "if" => {
+                    let x = reg(next()) << 8;
+                    let op = next();
+                    let rhs = if op == "key" || op == "-key" { None } else { Some(next()) };
+                    let begin = next() == "begin";
+                    let instr = match rhs {
+                        None if (op == "key") != begin => 0xE0A1,
+                        None => 0xE09E,
+                        Some(rhs) => {
+                            let equal = (op == "==") != begin;
+                            match rhs.starts_with('v') {
+                                true => (if equal { 0x9000 } else { 0x5000 }) | reg(rhs) << 4,
+                                false => (if equal { 0x4000 } else { 0x3000 }) | num(rhs),
+                            }
+                        }
+                    };
+                    emit(&mut out, instr | x);
+                    if begin {
+                        pending.push(out.len());
+                        emit(&mut out, 0x1000);
+                    }
+                }
+                "else" => {
+                    let at = pending.pop().unwrap();
+                    pending.push(out.len());
+                    emit(&mut out, 0x1000);
+                    patch(&mut out, at);
+                }
+                "end" => {
+                    let at = pending.pop().unwrap();
+                    patch(&mut out, at);
+                }
+                "clear" => emit(&mut out, 0x00E0),
+                "return" => emit(&mut out, 0x00EE),
+                "jump" => emit(&mut out, 0x1000 | addr(next(), labels)),
+                "jump0" => emit(&mut out, 0xB000 | addr(next(), labels)),
+                "sprite" => {
+                    let (x, y, n) = (reg(next()), reg(next()), num(next()));
+                    emit(&mut out, 0xD000 | x << 8 | y << 4 | n);
+                }
+                "bcd" => emit(&mut out, 0xF033 | reg(next()) << 8),
+                "save" => emit(&mut out, 0xF055 | reg(next()) << 8),
+                "load" => emit(&mut out, 0xF065 | reg(next()) << 8),
+                "delay" | "buzzer" => {
+                    assert_eq!(next(), ":=");
+                    emit(&mut out, (if token == "delay" { 0xF015 } else { 0xF018 }) | reg(next()) << 8);
+                }
+                "i" => match (next(), next()) {
+                    ("+=", x) => emit(&mut out, 0xF01E | reg(x) << 8),
+                    (":=", "hex") => emit(&mut out, 0xF029 | reg(next()) << 8),
+                    (_, target) => emit(&mut out, 0xA000 | addr(target, labels)),
+                },
+                _ if token.starts_with('v') => {
+                    let x = reg(token) << 8;
+                    let (op, rhs) = (next(), next());
+                    let instr = match (op, rhs) {
+                        (":=", "delay") => 0xF007,
+                        (":=", "key") => 0xF00A,
+                        (":=", "random") => 0xC000 | num(next()),
+                        (_, rhs) if rhs.starts_with('v') => {
+                            let n = match op {
+                                ":=" => 0x0,
+                                "|=" => 0x1,
+                                "&=" => 0x2,
+                                "^=" => 0x3,
+                                "+=" => 0x4,
+                                "-=" => 0x5,
+                                ">>=" => 0x6,
+                                "=-" => 0x7,
+                                "<<=" => 0xE,
+                                _ => panic!("unknown operator {}", op),
+                            };
+                            0x8000 | reg(rhs) << 4 | n
+                        }
+                        (":=", nn) => 0x6000 | num(nn),
+                        ("+=", nn) => 0x7000 | num(nn),
+                        _ => panic!("unknown statement {} {} {}", token, op, rhs),
+                    };
+                    emit(&mut out, instr | x);
+                }
+                _ if token.starts_with("0x") => out.push(num(token) as u8),
+                // anything else is a subroutine being called
+                _ => emit(&mut out, 0x2000 | addr(token, labels)),
+            }
+        }
+        assert!(pending.is_empty() && loops.is_empty(), "unbalanced begin/end or loop/again");
+        out
+    }
+
+    #[test]
+    fn skips_jumps_and_loops() {
+        let rom = [
+            0x60, 0x00, // v0 := 0
+            0x70, 0x01, // loop: v0 += 1
+            0x30, 0x05, // if v0 != 5 then
+            0x12, 0x0c, // jump over the else branch
+            0x61, 0x01, // v1 := 1
+            0x12, 0x0e,
+            0x61, 0x02, // v1 := 2
+            0x22, 0x14, // call the subroutine
+            0x12, 0x02, // again
+            0xaa, 0x55, // data
+            0x00, 0xe0, // subroutine: clear
+            0x00, 0xee, // return
+        ];
+        let source = decompile(&rom).unwrap();
+        let expected = "\
+: main
+  v0 := 0x00
+  loop
+    v0 += 0x01
+    if v0 == 0x05 begin
+      v1 := 0x01
+    else
+      v1 := 0x02
+    end
+    sub_214
+  again
+  0xaa 0x55
+
+: sub_214
+  clear
+  return
+";
+        assert_eq!(source, expected);
+        assert_eq!(assemble(&source), rom, "{}", source);
+    }
+
+    #[test]
+    fn rejects_roms_too_large_for_memory() {
+        let rom = vec![0; MAX_ROM_SIZE + 1];
+        assert_eq!(decompile(&rom), Err(VmError::RomTooLarge(MAX_ROM_SIZE + 1)));
+        assert!(decompile(&rom[..MAX_ROM_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn bundled_programs_reassemble() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/web/programs");
+        let mut checked = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            // each comes with a .txt describing it
+            if path.extension().is_none_or(|ext| ext != "ch8") {
+                continue;
+            }
+            let rom = fs::read(&path).unwrap();
+            assert!(assemble(&decompile(&rom).unwrap()) == rom, "{} doesn't reassemble to the same bytes", path.display());
+            checked += 1;
+        }
+        assert!(checked > 0, "no ROMs in {}", dir);
+    }
+}
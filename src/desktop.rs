@@ -0,0 +1,284 @@
+extern crate minifb;
+extern crate rodio;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use minifb::{Key, Window, WindowOptions};
+use rodio::{Sink, Source, OutputStream, OutputStreamHandle};
+
+use crate::audio::DEFAULT_SAMPLE_RATE;
+use crate::emulator::FrameInput;
+use crate::frontend::{AudioSink, InputSource, VideoSink};
+use crate::gamepad::Gamepad;
+use crate::palette::Palette;
+use crate::postfx::PostFx;
+use crate::scaler::Scaler;
+
+const FULLSCREEN_SIZE: (usize, usize) = (1920, 1080);
+const MAX_AUDIO_LATENCY: f32 = 0.1; // seconds of samples queued before the oldest are dropped
+
+pub fn get_first_key(keys: Vec<Key>) -> Option<u8> {
+    for key in keys.iter() {
+        let exponent = match key {
+            Key::Key1 => Some(0x1),
+            Key::Key2 => Some(0x2),
+            Key::Key3 => Some(0x3),
+            Key::Key4 => Some(0xC),
+            Key::Q =>    Some(0x4),
+            Key::W =>    Some(0x5),
+            Key::E =>    Some(0x6),
+            Key::R =>    Some(0xD),
+            Key::A =>    Some(0x7),
+            Key::S =>    Some(0x8),
+            Key::D =>    Some(0x9),
+            Key::F =>    Some(0xE),
+            Key::Z =>    Some(0xA),
+            Key::X =>    Some(0x0),
+            Key::C =>    Some(0xB),
+            Key::V =>    Some(0xF),
+            _ => None
+        };
+
+
+        if exponent.is_some() {
+            return exponent;
+        }
+    }
+
+    None
+}
+
+fn open_window(title: &str, size: (usize, usize), fullscreen: bool) -> Window {
+    // minifb can't switch a window to fullscreen, so that's a borderless window on top instead
+    let options = WindowOptions {
+        borderless: fullscreen,
+        title: !fullscreen,
+        resize: true,
+        topmost: fullscreen,
+        scale_mode: minifb::ScaleMode::UpperLeft, // the buffer always matches the window
+        ..WindowOptions::default()
+    };
+
+    let mut window = Window::new(title, size.0, size.1, options).unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+    if fullscreen {
+        window.set_position(0, 0);
+    }
+
+    // 60fps
+    window.limit_update_rate(Some(Duration::from_millis(1000/60)));
+    window
+}
+
+// minifb window used both for video and keyboard input
+pub struct MinifbFrontend {
+    pub window: Window,
+    pub gamepad: Option<Gamepad>,
+    pub palette: Palette,
+    pub scaler: Scaler,
+    pub postfx: PostFx,
+    pub fullscreen_size: (usize, usize), // minifb can't tell the monitor's resolution
+    title: String,
+    windowed_size: (usize, usize),       // to go back to when leaving fullscreen
+    fullscreen: bool,
+    pixels: Vec<u32>,                    // the last frame in palette colors, unscaled
+    pixels_size: (usize, usize),
+    buffer: Vec<u32>,                    // scaled to the window
+    buffer_size: (usize, usize),
+}
+
+impl MinifbFrontend {
+    // `scale` is the window's initial size in multiples of the display
+    pub fn new(title: &str, scale: usize, width: usize, height: usize) -> MinifbFrontend {
+        let size = (width * scale, height * scale);
+
+        MinifbFrontend {
+            window: open_window(title, size, false),
+            gamepad: None,
+            palette: Palette::default(),
+            scaler: Scaler::new(),
+            postfx: PostFx::default(),
+            fullscreen_size: FULLSCREEN_SIZE,
+            title: String::from(title),
+            windowed_size: size,
+            fullscreen: false,
+            pixels: vec![0; width * height],
+            pixels_size: (width, height),
+            buffer: Vec::new(),
+            buffer_size: (0, 0),
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = String::from(title);
+        self.window.set_title(title);
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.fullscreen
+    }
+
+    // reopens the window, keeping the picture
+    pub fn toggle_fullscreen(&mut self) {
+        self.fullscreen = !self.fullscreen;
+        let size = if self.fullscreen {
+            self.windowed_size = self.window.get_size();
+            self.fullscreen_size
+        } else {
+            self.windowed_size
+        };
+
+        self.window = open_window(&self.title, size, self.fullscreen);
+        self.redraw();
+    }
+
+    // scales the last frame to the window's current size
+    pub fn redraw(&mut self) {
+        let size = self.window.get_size();
+        if size.0 == 0 || size.1 == 0 {
+            // minimized
+            self.window.update();
+            return;
+        }
+
+        self.buffer.resize(size.0 * size.1, 0);
+        self.buffer_size = size;
+        self.scaler.render(&self.pixels, self.pixels_size, &mut self.buffer, size);
+        self.postfx.apply(&mut self.buffer, size);
+        self.window
+            .update_with_buffer(&self.buffer, size.0, size.1)
+            .unwrap();
+    }
+}
+
+impl VideoSink for MinifbFrontend {
+    fn present(&mut self, frame: &[u8], width: usize, height: usize) {
+        self.pixels.resize(width * height, 0);
+        self.pixels_size = (width, height);
+        self.palette.render_rgb(frame, &mut self.pixels);
+        self.redraw();
+    }
+
+    fn idle(&mut self) {
+        // keep the picture filling the window while it's resized with nothing new drawn
+        if self.window.get_size() != self.buffer_size {
+            self.redraw();
+            return;
+        }
+
+        // keep processing input (e.g. while paused) even when nothing was drawn
+        self.window.update();
+    }
+}
+
+impl InputSource for MinifbFrontend {
+    fn poll(&mut self) -> FrameInput {
+        let mut input = FrameInput::new();
+
+        if let Some(key) = get_first_key(self.window.get_keys()) {
+            input.set_key(key, true);
+        }
+
+        if let Some(gamepad) = self.gamepad.as_mut() {
+            gamepad.poll();
+            for key in gamepad.pressed_keys() {
+                input.set_key(key, true);
+            }
+        }
+
+        input
+    }
+
+    fn should_quit(&self) -> bool {
+        !self.window.is_open() || self.window.is_key_down(Key::Escape)
+    }
+}
+
+// feeds queued samples to rodio, playing silence whenever the emulator falls behind
+struct SampleQueue {
+    samples: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl Iterator for SampleQueue {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        Some(self.samples.lock().unwrap().pop_front().unwrap_or(0.0))
+    }
+}
+
+impl Source for SampleQueue {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// plays the emulator's generated audio through the default output device
+pub struct RodioAudio {
+    _stream: OutputStream, // has to outlive the sink
+    _handle: OutputStreamHandle,
+    _sink: Sink,
+    samples: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl Default for RodioAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RodioAudio {
+    pub fn new() -> RodioAudio {
+        let (stream, handle) = OutputStream::try_default().unwrap();
+        let sink = Sink::try_new(&handle).unwrap();
+        let samples = Arc::new(Mutex::new(VecDeque::new()));
+        let sample_rate = DEFAULT_SAMPLE_RATE;
+        sink.append(SampleQueue { samples: samples.clone(), sample_rate });
+
+        RodioAudio {
+            _stream: stream,
+            _handle: handle,
+            _sink: sink,
+            samples,
+            sample_rate,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+impl AudioSink for RodioAudio {
+    // the generated samples already follow the beeper
+    fn set_beep(&mut self, _on: bool) {}
+
+    fn queue(&mut self, samples: &[f32]) {
+        let mut queue = self.samples.lock().unwrap();
+        queue.extend(samples);
+
+        // e.g. when fast-forwarding, keep latency down rather than falling further behind
+        let max = (MAX_AUDIO_LATENCY * self.sample_rate as f32) as usize;
+        if queue.len() > max {
+            let excess = queue.len() - max;
+            queue.drain(..excess);
+        }
+    }
+}
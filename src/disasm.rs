@@ -0,0 +1,130 @@
+use crate::cfg::{Block, Cfg, Exit};
+use crate::memory::{Memory, LAST_INSTR_ADDR};
+use crate::quirks::Quirks;
+
+// mnemonics follow Cowgod's technical reference, unknown opcodes are shown as data
+pub fn disassemble(instr: u16) -> String {
+    let x = (instr & 0x0F00) >> 8;
+    let y = (instr & 0x00F0) >> 4;
+    let n = instr & 0x000F;
+    let nn = instr & 0x00FF;
+    let nnn = instr & 0x0FFF;
+
+    match instr & 0xF000 {
+        0x0000 => match instr {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            _ => format!("SYS {:#05x}", nnn),
+        },
+        0x1000 => format!("JP {:#05x}", nnn),
+        0x2000 => format!("CALL {:#05x}", nnn),
+        0x3000 => format!("SE V{:X}, {:#04x}", x, nn),
+        0x4000 => format!("SNE V{:X}, {:#04x}", x, nn),
+        0x5000 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, {:#04x}", x, nn),
+        0x7000 => format!("ADD V{:X}, {:#04x}", x, nn),
+        0x8000 => {
+            let op = match n {
+                0x0 => "LD",
+                0x1 => "OR",
+                0x2 => "AND",
+                0x3 => "XOR",
+                0x4 => "ADD",
+                0x5 => "SUB",
+                0x6 => "SHR",
+                0x7 => "SUBN",
+                0xE => "SHL",
+                _ => return data(instr),
+            };
+            format!("{} V{:X}, V{:X}", op, x, y)
+        }
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, {:#05x}", nnn),
+        0xB000 => format!("JP V0, {:#05x}", nnn),
+        0xC000 => format!("RND V{:X}, {:#04x}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => data(instr),
+        },
+        0xF000 => match nn {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => data(instr),
+        },
+        _ => data(instr),
+    }
+}
+
+fn data(instr: u16) -> String {
+    format!("DW {:#06x}", instr)
+}
+
+// where BNNN at `base` goes: NNN + V0, or XNN + VX with the CHIP-48 quirk
+pub fn computed_jump(base: u16, quirks: &Quirks) -> String {
+    let register = if quirks.jump_uses_vx { base >> 8 } else { 0 };
+    format!("V{:X} + {:03x}", register, base)
+}
+
+// disassembles `before` instructions before `addr` and `after` from it onwards,
+// as (address, opcode, mnemonic). addresses are stepped by 2 so data in between may misalign
+pub fn disassemble_around(memory: &Memory, addr: u16, before: u16, after: u16) -> Vec<(u16, u16, String)> {
+    let start = addr.saturating_sub(before * 2);
+    let end = addr.saturating_add(after * 2).min(LAST_INSTR_ADDR + 2);
+
+    (start..end)
+        .step_by(2)
+        .filter(|a| *a <= LAST_INSTR_ADDR)
+        .map(|a| {
+            let instr = memory.get_instr(a);
+            (a, instr, disassemble(instr))
+        })
+        .collect()
+}
+
+// a block's instructions, followed by a comment saying where it goes next
+pub fn disassemble_block(block: &Block, quirks: &Quirks) -> Vec<String> {
+    let mut lines: Vec<String> = block.instructions
+        .iter()
+        .map(|(addr, instr)| format!("  {:03x}  {:04x}  {}", addr, instr, disassemble(*instr)))
+        .collect();
+
+    lines.push(match block.exit {
+        Exit::Fallthrough(next) => format!("  ; falls through to {:03x}", next),
+        Exit::Jump(target) => format!("  ; jumps to {:03x}", target),
+        Exit::Call { target, ret } => format!("  ; calls {:03x}, then {:03x}", target, ret),
+        Exit::Skip { next, skipped } => format!("  ; {:03x}, or {:03x} when skipping", next, skipped),
+        Exit::Return => String::from("  ; returns"),
+        Exit::Computed(base) => format!("  ; jumps to {}, unresolved", computed_jump(base, quirks)),
+        Exit::End => String::from("  ; runs off the end of memory"),
+    });
+    lines
+}
+
+// the reachable code block by block, with a label on each and the subroutines marked.
+// anything between blocks is data, or only reached through computed jumps
+pub fn disassemble_cfg(cfg: &Cfg, quirks: &Quirks) -> String {
+    let mut listing = String::new();
+    for block in cfg.blocks.values() {
+        if cfg.functions.contains_key(&block.start) {
+            if !listing.is_empty() {
+                listing.push('\n');
+            }
+            listing.push_str(&format!("; function {:03x}\n", block.start));
+        }
+        listing.push_str(&format!("{:03x}:\n", block.start));
+        for line in disassemble_block(block, quirks) {
+            listing.push_str(&line);
+            listing.push('\n');
+        }
+    }
+    listing
+}
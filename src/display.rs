@@ -1,32 +1,87 @@
 pub const DISPLAY_WIDTH: usize = 64; 
 pub const DISPLAY_HEIGHT: usize = 32; 
 
+// region of the display in pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn union(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect { x, y, width: right - x, height: bottom - y }
+    }
+}
+
+const FULL: Rect = Rect { x: 0, y: 0, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT };
+
 pub struct Display {
     pub pixels: [u8; DISPLAY_WIDTH*DISPLAY_HEIGHT], // display graphics
+    dirty: Option<Rect>, // changed since the last `take_dirty`
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Display {
     pub fn new() -> Display {
         Display {
             pixels: [0; DISPLAY_WIDTH*DISPLAY_HEIGHT],
+            dirty: Some(FULL),
         }
     }
 
+    pub fn width(&self) -> usize {
+        DISPLAY_WIDTH
+    }
+
+    pub fn height(&self) -> usize {
+        DISPLAY_HEIGHT
+    }
+
     pub fn as_ptr(&self) -> *const u8 {
         self.pixels.as_ptr()
     } 
 
     pub fn clear(&mut self) {
         self.pixels.fill(0);
+        self.dirty = Some(FULL);
+    }
+
+    // for when `pixels` was written to directly
+    pub fn invalidate(&mut self) {
+        self.dirty = Some(FULL);
+    }
+
+    // the smallest area covering every pixel changed since the last call
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
     }
 
-    pub fn draw(&mut self, x: usize, y: usize, sprite_height: usize, sprite: &[u8]) -> u8 {
+    // with `clip` set, the starting position still wraps but pixels past the edge are dropped
+    pub fn draw(&mut self, x: usize, y: usize, sprite_height: usize, sprite: &[u8], clip: bool) -> u8 {
         let mut collide_flag: u8 = 0;
+        let (x, y) = (x % DISPLAY_WIDTH, y % DISPLAY_HEIGHT);
+        let (mut left, mut top, mut right, mut bottom) = (DISPLAY_WIDTH, DISPLAY_HEIGHT, 0, 0);
 
         for row in 0..sprite_height {
-            let mut sprite: u8 = sprite[row as usize];
+            let mut sprite: u8 = sprite[row];
 
             for col in (0..8).rev() {
+                if clip && (x + col >= DISPLAY_WIDTH || y + row >= DISPLAY_HEIGHT) {
+                    sprite >>= 1;
+                    continue;
+                }
+
                 let vx_w = (x + col) % DISPLAY_WIDTH; 
                 let vy_w = (y + row) % DISPLAY_HEIGHT;
 
@@ -35,10 +90,22 @@ impl Display {
                     collide_flag = 1;
                 }
                 self.pixels[i] ^= sprite & 0x1; 
+                if sprite & 0x1 == 0x1 {
+                    // wrapped sprites are split across the edges, so this can cover most of a row
+                    left = left.min(vx_w);
+                    right = right.max(vx_w + 1);
+                    top = top.min(vy_w);
+                    bottom = bottom.max(vy_w + 1);
+                }
                 sprite >>= 1;
             }
         }
 
+        if left < right {
+            let rect = Rect { x: left, y: top, width: right - left, height: bottom - top };
+            self.dirty = Some(self.dirty.map_or(rect, |dirty| dirty.union(&rect)));
+        }
+
         collide_flag
     }
 }
@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::vm::VM;
+use crate::memory::MEM_SIZE;
+use crate::audio::AudioGenerator;
+use crate::error::VmError;
+use crate::filter::{DisplayFilter, Filter};
+use crate::profiler::Profiler;
+use crate::romdb::{self, RomInfo};
+use crate::scheduler::{Scheduler, DEFAULT_CYCLES_PER_FRAME};
+use crate::stack::MAX_STACK_DEPTH;
+use crate::quirks::Quirks;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::timing::{self, TimingMode, VIP_CPU_CYCLES_PER_FRAME};
+
+// keys held down for the frame, as a bit field like `Keypad`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameInput {
+    pub keys: u16,
+}
+
+impl FrameInput {
+    pub fn new() -> FrameInput {
+        FrameInput { keys: 0 }
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        if pressed {
+            self.keys |= 1 << key;
+        } else {
+            self.keys &= !(1 << key);
+        }
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        (self.keys & 1 << key) > 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameOutput {
+    pub frames: u32,             // number of 60Hz frames that were emulated
+    pub redraw: bool,            // display changed and should be presented
+    pub sound: bool,             // beeper should be on
+    pub breakpoint: Option<u16>, // execution stopped at this breakpoint, mid-frame
+    pub error: Option<VmError>,  // execution stopped at an instruction that can't run
+}
+
+pub struct Emulator {
+    pub vm: VM,
+    pub scheduler: Scheduler,
+    pub breakpoints: HashSet<u16>,
+    pub timing: TimingMode,
+    pub audio: Option<AudioGenerator>, // set by frontends that play PCM rather than toggling a beeper
+    pub filter: DisplayFilter,
+    pub profiler: Option<Profiler>,
+    rom_info: Option<&'static RomInfo>,
+    cycle_in_frame: u32,    // instructions already run in the current frame
+    vip_cycles_left: i32,   // machine cycles left this frame, negative if the last instruction overran
+    vblank_wait: bool,      // a DXYN is waiting for the next frame to start
+    skip_breakpoint: bool,  // resuming from a breakpoint, don't stop on it again
+    erased: bool,           // the last sprite drawn this frame turned pixels off
+    sound: bool,            // beeper state as of the last completed frame
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        Emulator {
+            vm: VM::new(),
+            scheduler: Scheduler::default(),
+            breakpoints: HashSet::new(),
+            timing: TimingMode::Instructions,
+            audio: None,
+            filter: DisplayFilter::new(),
+            profiler: None,
+            rom_info: None,
+            cycle_in_frame: 0,
+            vip_cycles_left: VIP_CPU_CYCLES_PER_FRAME,
+            vblank_wait: false,
+            skip_breakpoint: false,
+            erased: false,
+            sound: false,
+        }
+    }
+
+    // resets with a new program and applies quirks and speed from the ROM database, or the
+    // defaults for programs it doesn't know
+    pub fn load_program(&mut self, buf: &[u8]) -> Result<Option<&'static RomInfo>, VmError> {
+        self.vm.load_rom(buf)?;
+        self.reset_frame();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.clear();
+        }
+
+        self.rom_info = romdb::lookup(buf);
+        match self.rom_info {
+            Some(info) => {
+                self.vm.quirks = info.quirks;
+                self.vm.stack.set_depth(info.platform.stack_depth());
+            }
+            None => {
+                self.vm.quirks = Quirks::default();
+                self.vm.stack.set_depth(MAX_STACK_DEPTH);
+            }
+        }
+        self.scheduler.cycles_per_frame = self.rom_info
+            .and_then(|info| info.cycles_per_frame)
+            .unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+
+        Ok(self.rom_info)
+    }
+
+    // restarts the loaded program, keeping quirks, speed and other settings
+    pub fn reset(&mut self) {
+        self.vm.reset();
+        self.reset_frame();
+    }
+
+    fn reset_frame(&mut self) {
+        self.cycle_in_frame = 0;
+        self.vip_cycles_left = VIP_CPU_CYCLES_PER_FRAME;
+        self.vblank_wait = false;
+        self.skip_breakpoint = false;
+        self.erased = false;
+        self.sound = false;
+        if let Some(audio) = self.audio.as_mut() {
+            audio.set_beep(false, 0.0);
+        }
+    }
+
+    pub fn rom_info(&self) -> Option<&'static RomInfo> {
+        self.rom_info
+    }
+
+    pub fn get_display(&self) -> &[u8] {
+        self.vm.get_display()
+    }
+
+    // (width, height) of `frame`
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.vm.display.width(), self.vm.display.height())
+    }
+
+    // what to present: the display's brightness from 0 to 255 per pixel, after filtering
+    pub fn frame(&mut self) -> &[u8] {
+        self.filter.levels(&self.vm.display)
+    }
+
+    // snapshot of the machine, including how far into the current frame it is.
+    // settings such as speed and breakpoints belong to the frontend and aren't saved
+    pub fn save_state(&self) -> Result<Vec<u8>, StateError> {
+        let mut w = StateWriter::new();
+        self.vm.save_state(&mut w)?;
+        w.u32(self.cycle_in_frame);
+        w.i32(self.vip_cycles_left);
+        w.bool(self.vblank_wait);
+        w.bool(self.sound);
+        Ok(w.finish())
+    }
+
+    // leaves the emulator untouched if the state can't be read
+    pub fn load_state(&mut self, buf: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(buf)?;
+        let mut vm = VM::new();
+        vm.load_state(&mut r)?;
+        let cycle_in_frame = r.u32()?;
+        let vip_cycles_left = r.i32()?;
+        let vblank_wait = r.bool()?;
+        let sound = r.bool()?;
+
+        // states don't include the program, it stays whatever was loaded
+        vm.rom = std::mem::take(&mut self.vm.rom);
+        vm.sanitizer = self.vm.sanitizer.take();
+        if let Some(sanitizer) = vm.sanitizer.as_mut() {
+            // no telling what was written before the state was saved
+            sanitizer.mark_written(0, MEM_SIZE);
+        }
+        vm.coverage = self.vm.coverage.take();
+        vm.selfmod = self.vm.selfmod.take();
+        self.vm = vm;
+        self.cycle_in_frame = cycle_in_frame;
+        self.vip_cycles_left = vip_cycles_left;
+        self.vblank_wait = vblank_wait;
+        self.sound = sound;
+        self.skip_breakpoint = false;
+        if let Some(audio) = self.audio.as_mut() {
+            audio.set_beep(self.vm.sound_t > 0, 0.0);
+        }
+        Ok(())
+    }
+
+    // runs however many frames the scheduler says are due after `elapsed` wall time
+    pub fn update(&mut self, elapsed: Duration, input: &FrameInput) -> FrameOutput {
+        let mut output = FrameOutput::default();
+
+        for _ in 0..self.scheduler.frames_due(elapsed) {
+            let frame = self.run_frame(input);
+            output.frames += frame.frames;
+            output.redraw |= frame.redraw;
+
+            if frame.breakpoint.is_some() || frame.error.is_some() {
+                output.breakpoint = frame.breakpoint;
+                output.error = frame.error;
+                self.scheduler.set_paused(true);
+                break;
+            }
+        }
+
+        // the host may update faster than 60Hz, so keep beeping between emulated frames
+        output.sound = self.sound && !self.scheduler.is_paused();
+        output
+    }
+
+    // runs the rest of the current frame and one timer tick. the frame ends after
+    // `cycles_per_frame` instructions, or once the VIP cycle budget is spent in VIP timing mode
+    pub fn run_frame(&mut self, input: &FrameInput) -> FrameOutput {
+        let mut output = FrameOutput::default();
+        self.apply_input(input);
+
+        while !self.frame_done() {
+            if !self.skip_breakpoint && self.breakpoints.contains(&self.vm.pc) {
+                self.skip_breakpoint = true;
+                output.breakpoint = Some(self.vm.pc);
+                return output;
+            }
+
+            match self.execute() {
+                Ok(redraw) => output.redraw |= redraw,
+                Err(err) => {
+                    output.error = Some(err);
+                    return output;
+                }
+            }
+        }
+
+        output.frames = 1;
+        output.sound = self.end_frame(&mut output.redraw);
+        output
+    }
+
+    // runs a single instruction, finishing the frame if it was the last one in it
+    pub fn step(&mut self, input: &FrameInput) -> FrameOutput {
+        let mut output = FrameOutput::default();
+        self.apply_input(input);
+
+        match self.execute() {
+            Ok(redraw) => output.redraw = redraw,
+            Err(err) => {
+                output.error = Some(err);
+                return output;
+            }
+        }
+        if self.frame_done() {
+            output.frames = 1;
+            output.sound = self.end_frame(&mut output.redraw);
+        }
+
+        output
+    }
+
+    fn apply_input(&mut self, input: &FrameInput) {
+        self.vm.reset_keys();
+        for key in 0..16 {
+            if input.is_pressed(key) {
+                self.vm.set_key(Some(key), true);
+            }
+        }
+    }
+
+    fn frame_done(&self) -> bool {
+        self.vblank_wait || match self.timing {
+            TimingMode::Instructions => self.cycle_in_frame >= self.scheduler.cycles_per_frame,
+            TimingMode::CosmacVip => self.vip_cycles_left <= 0,
+        }
+    }
+
+    // a failed instruction is left at PC, so running again fails the same way
+    fn execute(&mut self) -> Result<bool, VmError> {
+        let pc = self.vm.pc;
+        let registers = self.vm.registers;
+        let beeping = self.vm.sound_t > 0;
+
+        self.skip_breakpoint = false;
+        self.vm.redraw = false;
+        let instr = self.vm.emulate_cycle()?;
+        self.cycle_in_frame += 1;
+
+        // every skip instruction lands 4 bytes ahead when taken
+        let skipped = self.vm.pc == pc.wrapping_add(4);
+        let cycles = || timing::vip_instruction_cycles(instr, &registers, skipped);
+        if self.timing == TimingMode::CosmacVip {
+            self.vip_cycles_left -= cycles() as i32;
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, instr, cycles(), &self.vm);
+        }
+
+        if instr & 0xF000 == 0xD000 {
+            self.erased = self.vm.registers[0xF] != 0;
+            if self.vm.quirks.display_wait {
+                self.vblank_wait = true;
+            }
+        }
+
+        if beeping != (self.vm.sound_t > 0) {
+            let position = self.frame_position();
+            if let Some(audio) = self.audio.as_mut() {
+                audio.set_beep(!beeping, position);
+            }
+        }
+
+        // filtered output only changes at the end of a frame
+        Ok(self.vm.redraw && self.filter.mode() == Filter::Off)
+    }
+
+    // timers tick exactly once per emulated frame, i.e. at 60Hz of emulated time,
+    // which on the VIP happens in the interrupt at the start of each frame
+    fn end_frame(&mut self, redraw: &mut bool) -> bool {
+        if let Some(profiler) = self.profiler.as_mut() {
+            let waiting = self.vblank_wait && self.timing == TimingMode::CosmacVip;
+            profiler.end_frame(if waiting { self.vip_cycles_left.max(0) as u32 } else { 0 });
+        }
+
+        // time spent waiting for the vertical blank is lost, but an overrun carries over
+        if self.vblank_wait {
+            self.vip_cycles_left = self.vip_cycles_left.min(0);
+        }
+        self.vip_cycles_left += VIP_CPU_CYCLES_PER_FRAME;
+        self.vblank_wait = false;
+        self.cycle_in_frame = 0;
+        if self.filter.mode() != Filter::Off {
+            *redraw |= self.filter.end_frame(&self.vm.display, self.erased);
+        }
+        self.erased = false;
+        self.sound = self.vm.decrement_timers();
+
+        if let Some(audio) = self.audio.as_mut() {
+            audio.end_frame();
+            if self.sound && self.vm.sound_t == 0 {
+                audio.set_beep(false, 0.0);
+            }
+        }
+
+        self.sound
+    }
+
+    // how far through the current frame execution is, from 0 to 1
+    fn frame_position(&self) -> f32 {
+        match self.timing {
+            TimingMode::Instructions => self.cycle_in_frame as f32 / self.scheduler.cycles_per_frame.max(1) as f32,
+            TimingMode::CosmacVip => 1.0 - self.vip_cycles_left as f32 / VIP_CPU_CYCLES_PER_FRAME as f32,
+        }
+    }
+}
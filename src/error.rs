@@ -0,0 +1,24 @@
+use std::fmt;
+
+use crate::vm::MAX_ROM_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmError {
+    RomTooLarge(usize),   // size of the ROM in bytes
+    StackOverflow(u16),   // address of the 2NNN that had nowhere to put its return address
+    StackUnderflow(u16),  // address of the 00EE with nothing to return to
+    PcOutOfRange(u16),    // PC with no room left in memory for a whole instruction
+    InvalidOpcode(u16, u16), // address and opcode of an instruction CHIP-8 doesn't define
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::RomTooLarge(size) => write!(f, "ROM is {} bytes, at most {} fit in memory", size, MAX_ROM_SIZE),
+            VmError::StackOverflow(pc) => write!(f, "stack overflow at {:#05x}, too many nested calls", pc),
+            VmError::StackUnderflow(pc) => write!(f, "stack underflow at {:#05x}, return without a call", pc),
+            VmError::PcOutOfRange(pc) => write!(f, "PC at {:#x}, past the end of memory", pc),
+            VmError::InvalidOpcode(pc, opcode) => write!(f, "invalid opcode {:04x} at {:#05x}", opcode, pc),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+
+use crate::display::{Display, DISPLAY_WIDTH, DISPLAY_HEIGHT};
+
+const NUM_PIXELS: usize = DISPLAY_WIDTH*DISPLAY_HEIGHT;
+pub const DEFAULT_PHOSPHOR_FRAMES: u8 = 4;
+
+// CHIP-8 games erase sprites by drawing them again, so anything that moves flickers.
+// filters work on whole emulated frames and produce a brightness from 0 to 255 per pixel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Off,
+    Blend,         // average of the last two frames
+    Phosphor(u8),  // lit pixels fade out over this many frames
+    Stable,        // only show frames whose last sprite draw didn't erase anything
+}
+
+impl Filter {
+    // "off", "blend", "stable", or "phosphor" with an optional number of frames, e.g. "phosphor:8"
+    pub fn parse(s: &str) -> Option<Filter> {
+        match s.split_once(':') {
+            Some(("phosphor", frames)) => frames.parse().ok().filter(|f| *f > 0).map(Filter::Phosphor),
+            Some(_) => None,
+            None => match s {
+                "off" => Some(Filter::Off),
+                "blend" => Some(Filter::Blend),
+                "phosphor" => Some(Filter::Phosphor(DEFAULT_PHOSPHOR_FRAMES)),
+                "stable" => Some(Filter::Stable),
+                _ => None,
+            },
+        }
+    }
+
+    // for hotkeys that cycle through the filters
+    pub fn next(self) -> Filter {
+        match self {
+            Filter::Off => Filter::Blend,
+            Filter::Blend => Filter::Phosphor(DEFAULT_PHOSPHOR_FRAMES),
+            Filter::Phosphor(_) => Filter::Stable,
+            Filter::Stable => Filter::Off,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    // the same names `parse` accepts
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Off => f.write_str("off"),
+            Filter::Blend => f.write_str("blend"),
+            Filter::Phosphor(frames) => write!(f, "phosphor:{}", frames),
+            Filter::Stable => f.write_str("stable"),
+        }
+    }
+}
+
+pub struct DisplayFilter {
+    mode: Filter,
+    levels: [u8; NUM_PIXELS],   // what gets presented
+    previous: [u8; NUM_PIXELS], // display at the end of the last frame, for blending
+}
+
+impl Default for DisplayFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DisplayFilter {
+    pub fn new() -> DisplayFilter {
+        DisplayFilter {
+            mode: Filter::Off,
+            levels: [0; NUM_PIXELS],
+            previous: [0; NUM_PIXELS],
+        }
+    }
+
+    pub fn mode(&self) -> Filter {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Filter) {
+        self.mode = mode;
+    }
+
+    // call at the end of every emulated frame. `erased` is whether the frame's last sprite
+    // draw turned pixels off. returns whether the output changed
+    pub fn end_frame(&mut self, display: &Display, erased: bool) -> bool {
+        let before = self.levels;
+
+        match self.mode {
+            Filter::Off => self.show(display),
+            Filter::Blend => {
+                for ((level, px), prev) in self.levels.iter_mut().zip(display.pixels.iter()).zip(self.previous.iter()) {
+                    *level = ((lit(*px) as u16 + lit(*prev) as u16) / 2) as u8;
+                }
+            }
+            Filter::Phosphor(frames) => {
+                let decay = (255 / frames as u16).max(1) as u8;
+                for (level, px) in self.levels.iter_mut().zip(display.pixels.iter()) {
+                    *level = if *px != 0 { 255 } else { level.saturating_sub(decay) };
+                }
+            }
+            Filter::Stable => {
+                if !erased {
+                    self.show(display);
+                }
+            }
+        }
+
+        self.previous.copy_from_slice(&display.pixels);
+        self.levels != before
+    }
+
+    // the brightness of each pixel. without a filter this always follows the display,
+    // including changes in the middle of a frame (e.g. when single stepping)
+    pub fn levels(&mut self, display: &Display) -> &[u8] {
+        if self.mode == Filter::Off {
+            self.show(display);
+        }
+        &self.levels
+    }
+
+    fn show(&mut self, display: &Display) {
+        for (level, px) in self.levels.iter_mut().zip(display.pixels.iter()) {
+            *level = lit(*px);
+        }
+    }
+}
+
+fn lit(px: u8) -> u8 {
+    if px != 0 { 255 } else { 0 }
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+#[cfg(not(target_arch="wasm32"))]
+use std::time::Instant;
+
+use crate::emulator::{Emulator, FrameInput, FrameOutput};
+
+// traits a frontend implements so the emulator loop can be shared between backends
+
+pub trait VideoSink {
+    // called whenever the display changed, with each pixel's brightness from 0 to 255.
+    // the resolution is passed every time since programs can switch it while running
+    fn present(&mut self, frame: &[u8], width: usize, height: usize);
+
+    // called on host updates where nothing was drawn, e.g. to keep a window responsive
+    fn idle(&mut self) {}
+}
+
+pub trait AudioSink {
+    fn set_beep(&mut self, on: bool);
+
+    // PCM from the emulator's `AudioGenerator`, if the frontend set one up
+    fn queue(&mut self, _samples: &[f32]) {}
+}
+
+pub trait InputSource {
+    fn poll(&mut self) -> FrameInput;
+
+    fn should_quit(&self) -> bool {
+        false
+    }
+}
+
+pub trait Clock {
+    // wall time since the previous call
+    fn elapsed(&mut self) -> Duration;
+}
+
+// real time, for interactive frontends
+#[cfg(not(target_arch="wasm32"))]
+pub struct SystemClock {
+    last: Instant,
+}
+
+#[cfg(not(target_arch="wasm32"))]
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch="wasm32"))]
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock { last: Instant::now() }
+    }
+}
+
+#[cfg(not(target_arch="wasm32"))]
+impl Clock for SystemClock {
+    fn elapsed(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        elapsed
+    }
+}
+
+// shows the emulator's current frame, e.g. after single stepping
+pub fn present<S: VideoSink>(emulator: &mut Emulator, screen: &mut S) {
+    let (width, height) = emulator.resolution();
+    screen.present(emulator.frame(), width, height);
+}
+
+// one host update: read input, run whatever frames are due and present the result
+// `screen` is both input and video since windowing libraries usually own both
+pub fn tick<C, S, A>(emulator: &mut Emulator, clock: &mut C, screen: &mut S, audio: &mut A) -> FrameOutput
+where
+    C: Clock,
+    S: InputSource + VideoSink,
+    A: AudioSink,
+{
+    let frame_input = screen.poll();
+    let output = emulator.update(clock.elapsed(), &frame_input);
+
+    if output.redraw {
+        present(emulator, screen);
+    } else {
+        screen.idle();
+    }
+    audio.set_beep(output.sound);
+    if let Some(generator) = emulator.audio.as_mut() {
+        audio.queue(generator.samples());
+        generator.clear();
+    }
+
+    output
+}
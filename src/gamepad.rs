@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gilrs::{Axis, Button, Gilrs};
+
+use crate::romdb::KeyBindings;
+
+const STICK_DEADZONE: f32 = 0.5; // how far a stick must be pushed to count as a press
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Binding {
+    Button(Button),
+    Stick(Axis, bool), // axis and direction (true = positive)
+}
+
+impl Binding {
+    pub fn from_name(name: &str) -> Option<Binding> {
+        let binding = match name {
+            "DPadUp" =>        Binding::Button(Button::DPadUp),
+            "DPadDown" =>      Binding::Button(Button::DPadDown),
+            "DPadLeft" =>      Binding::Button(Button::DPadLeft),
+            "DPadRight" =>     Binding::Button(Button::DPadRight),
+            "South" =>         Binding::Button(Button::South),
+            "East" =>          Binding::Button(Button::East),
+            "North" =>         Binding::Button(Button::North),
+            "West" =>          Binding::Button(Button::West),
+            "LeftTrigger" =>   Binding::Button(Button::LeftTrigger),
+            "LeftTrigger2" =>  Binding::Button(Button::LeftTrigger2),
+            "RightTrigger" =>  Binding::Button(Button::RightTrigger),
+            "RightTrigger2" => Binding::Button(Button::RightTrigger2),
+            "Select" =>        Binding::Button(Button::Select),
+            "Start" =>         Binding::Button(Button::Start),
+            "LeftThumb" =>     Binding::Button(Button::LeftThumb),
+            "RightThumb" =>    Binding::Button(Button::RightThumb),
+            // gilrs reports up as positive on the Y axis
+            "StickUp" =>       Binding::Stick(Axis::LeftStickY, true),
+            "StickDown" =>     Binding::Stick(Axis::LeftStickY, false),
+            "StickLeft" =>     Binding::Stick(Axis::LeftStickX, false),
+            "StickRight" =>    Binding::Stick(Axis::LeftStickX, true),
+            _ => return None
+        };
+
+        Some(binding)
+    }
+}
+
+pub struct GamepadMapping {
+    bindings: Vec<(Binding, u8)>, // gamepad input -> CHIP-8 key
+}
+
+impl Default for GamepadMapping {
+    fn default() -> GamepadMapping {
+        // directions follow the 2/4/6/8 layout most games use, stick mirrors the d-pad
+        let defaults = [
+            ("DPadUp", 0x2), ("DPadDown", 0x8), ("DPadLeft", 0x4), ("DPadRight", 0x6),
+            ("StickUp", 0x2), ("StickDown", 0x8), ("StickLeft", 0x4), ("StickRight", 0x6),
+            ("South", 0x5), ("East", 0xA), ("West", 0x0), ("North", 0xB),
+            ("Select", 0xE), ("Start", 0xF),
+        ];
+
+        GamepadMapping {
+            bindings: defaults
+                .iter()
+                .map(|(name, key)| (Binding::from_name(name).unwrap(), *key))
+                .collect(),
+        }
+    }
+}
+
+impl GamepadMapping {
+    // default mapping with d-pad, stick and the two main face buttons moved to the ROM's controls
+    pub fn from_keys(keys: &KeyBindings) -> GamepadMapping {
+        let mut mapping = GamepadMapping::default();
+        let controls: [(Option<u8>, &[&str]); 6] = [
+            (keys.up, &["DPadUp", "StickUp"]),
+            (keys.down, &["DPadDown", "StickDown"]),
+            (keys.left, &["DPadLeft", "StickLeft"]),
+            (keys.right, &["DPadRight", "StickRight"]),
+            (keys.a, &["South"]),
+            (keys.b, &["East"]),
+        ];
+
+        for (key, names) in controls.iter() {
+            if let Some(key) = key {
+                for name in names.iter() {
+                    mapping.bind(Binding::from_name(name).unwrap(), *key);
+                }
+            }
+        }
+
+        mapping
+    }
+
+    pub fn bind(&mut self, binding: Binding, key: u8) {
+        self.bindings.retain(|(b, _)| *b != binding);
+        self.bindings.push((binding, key));
+    }
+
+    // parses lines of the form `<input> = <hex key>`, e.g. `DPadUp = 5`
+    // inputs that are not listed keep their binding from `base`
+    pub fn parse(text: &str, base: GamepadMapping) -> Result<GamepadMapping, String> {
+        let mut mapping = base;
+
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, key) = line
+                .split_once('=')
+                .ok_or(format!("line {}: expected `<input> = <key>`", line_no + 1))?;
+
+            let binding = Binding::from_name(name.trim())
+                .ok_or(format!("line {}: unknown gamepad input `{}`", line_no + 1, name.trim()))?;
+
+            let key = u8::from_str_radix(key.trim().trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|k| *k <= 0xF)
+                .ok_or(format!("line {}: `{}` is not a CHIP-8 key (0-F)", line_no + 1, key.trim()))?;
+
+            mapping.bind(binding, key);
+        }
+
+        Ok(mapping)
+    }
+
+    pub fn load(path: &Path, base: GamepadMapping) -> Result<GamepadMapping, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        GamepadMapping::parse(&text, base).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // per-ROM mapping lives next to the ROM, e.g. `Pong.ch8` -> `Pong.pad`
+    pub fn rom_mapping_path(rom_path: &Path) -> PathBuf {
+        rom_path.with_extension("pad")
+    }
+}
+
+pub struct Gamepad {
+    gilrs: Option<Gilrs>,
+    mapping: GamepadMapping,
+    keys: u16, // bit field of CHIP-8 keys held on any connected gamepad
+}
+
+impl Gamepad {
+    pub fn new(mapping: GamepadMapping) -> Gamepad {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(e) => {
+                eprintln!("Gamepad support unavailable: {}", e);
+                None
+            }
+        };
+
+        Gamepad {
+            gilrs,
+            mapping,
+            keys: 0,
+        }
+    }
+
+    // e.g. for another ROM, keeping the connection to the gamepads
+    pub fn set_mapping(&mut self, mapping: GamepadMapping) {
+        self.mapping = mapping;
+        self.keys = 0;
+    }
+
+    pub fn poll(&mut self) {
+        let gilrs = match self.gilrs.as_mut() {
+            Some(gilrs) => gilrs,
+            None => return,
+        };
+
+        // drain events so gilrs updates its cached gamepad state
+        while gilrs.next_event().is_some() {}
+
+        self.keys = 0;
+        for (_, gamepad) in gilrs.gamepads() {
+            for (binding, key) in self.mapping.bindings.iter() {
+                let held = match *binding {
+                    Binding::Button(button) => gamepad.is_pressed(button),
+                    Binding::Stick(axis, positive) => {
+                        let value = gamepad.value(axis);
+                        if positive { value > STICK_DEADZONE } else { value < -STICK_DEADZONE }
+                    }
+                };
+
+                if held {
+                    self.keys |= 1 << key;
+                }
+            }
+        }
+    }
+
+    pub fn pressed_keys(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..16u8).filter(move |key| (self.keys & 1 << key) > 0)
+    }
+}
@@ -2,6 +2,12 @@ pub struct Keypad {
     keys: u16, // bit field of which keys were pressed
 }
 
+impl Default for Keypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad {
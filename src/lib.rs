@@ -1,7 +1,44 @@
 pub mod vm;
 pub mod display;
 pub mod memory;
+pub mod stack;
+pub mod sanitizer;
 pub mod keypad;
+pub mod quirks;
+pub mod romdb;
+pub mod scheduler;
+pub mod timing;
+pub mod emulator;
+pub mod audio;
+pub mod palette;
+pub mod filter;
+pub mod scaler;
+pub mod postfx;
+pub mod config;
+pub mod state;
+pub mod error;
+pub mod frontend;
+pub mod disasm;
+pub mod cfg;
+pub mod decompile;
+pub mod coverage;
+pub mod profiler;
+pub mod selfmod;
+
+#[cfg(all(feature = "desktop", not(target_arch="wasm32")))]
+pub mod gamepad;
+
+#[cfg(all(feature = "desktop", not(target_arch="wasm32")))]
+pub mod desktop;
 
 #[cfg(target_arch="wasm32")]
 pub mod wasm;
+
+#[cfg(all(feature = "tui", not(target_arch="wasm32")))]
+pub mod terminal;
+
+#[cfg(not(target_arch="wasm32"))]
+pub mod watch;
+
+#[cfg(all(feature = "libretro", not(target_arch="wasm32")))]
+pub mod libretro;
@@ -0,0 +1,479 @@
+// libretro core, so the emulator can run inside RetroArch and other libretro frontends.
+// the API is small enough that it's declared by hand rather than through bindings
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::display::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use crate::audio::AudioGenerator;
+use crate::emulator::{Emulator, FrameInput};
+use crate::error::VmError;
+use crate::palette::Palette;
+use crate::romdb::{KeyBindings, Platform};
+use crate::scheduler::{DEFAULT_CYCLES_PER_FRAME, FRAME_RATE};
+use crate::stack::MAX_STACK_DEPTH;
+use crate::state::STATE_SIZE;
+use crate::timing::TimingMode;
+
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const RETRO_ENVIRONMENT_GET_LOG_INTERFACE: c_uint = 27;
+
+const RETRO_LOG_ERROR: c_uint = 3;
+
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_DEVICE_ID_JOYPAD_B: c_uint = 0;
+const RETRO_DEVICE_ID_JOYPAD_Y: c_uint = 1;
+const RETRO_DEVICE_ID_JOYPAD_SELECT: c_uint = 2;
+const RETRO_DEVICE_ID_JOYPAD_START: c_uint = 3;
+const RETRO_DEVICE_ID_JOYPAD_UP: c_uint = 4;
+const RETRO_DEVICE_ID_JOYPAD_DOWN: c_uint = 5;
+const RETRO_DEVICE_ID_JOYPAD_LEFT: c_uint = 6;
+const RETRO_DEVICE_ID_JOYPAD_RIGHT: c_uint = 7;
+const RETRO_DEVICE_ID_JOYPAD_A: c_uint = 8;
+const RETRO_DEVICE_ID_JOYPAD_X: c_uint = 9;
+
+const SAMPLE_RATE: u32 = 44100;
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+type LogFn = unsafe extern "C" fn(level: c_uint, fmt: *const c_char, ...);
+
+#[repr(C)]
+struct RetroLogCallback {
+    log: Option<LogFn>,
+}
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    geometry: RetroGameGeometry,
+    timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct RetroVariable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+// core options, as `key` and `description; default|other values`
+const VARIABLES: [(&CStr, &CStr); 4] = [
+    (c"rchip_platform", c"Quirks; auto|vip|chip48|modern"),
+    (c"rchip_cycles", c"Cycles per frame; auto|5|10|15|20|30|50|100|200|500|1000"),
+    (c"rchip_timing", c"Timing; instructions|vip"),
+    (c"rchip_palette", c"Palette; auto|classic|amber|lcd|octo"),
+];
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+    log: Option<LogFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+    log: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+struct Core {
+    emulator: Emulator,
+    joypad: Vec<(c_uint, u8)>,   // retropad button and the CHIP-8 key it presses
+    video: Vec<u32>,
+    audio: Vec<i16>,             // stereo frames for the frontend
+    palette: Palette,
+    error: Option<VmError>, // the last one logged, as a stopped program fails the same way every frame
+}
+
+impl Core {
+    fn new(rom: &[u8]) -> Result<Core, VmError> {
+        let mut emulator = Emulator::new();
+        emulator.audio = Some(AudioGenerator::new(SAMPLE_RATE));
+        let info = emulator.load_program(rom)?;
+
+        let mut core = Core {
+            emulator,
+            joypad: joypad_bindings(&info.map(|info| info.keys).unwrap_or_default()),
+            video: vec![0; DISPLAY_WIDTH*DISPLAY_HEIGHT],
+            audio: Vec::new(),
+            palette: Palette::default(),
+            error: None,
+        };
+        core.apply_options();
+        Ok(core)
+    }
+
+    fn reset(&mut self) {
+        self.emulator.reset();
+        self.apply_options();
+    }
+
+    // "auto" options fall back to whatever the ROM database says
+    fn apply_options(&mut self) {
+        let info = self.emulator.rom_info();
+
+        let platform = get_variable(VARIABLES[0].0).as_deref().and_then(Platform::parse);
+        self.emulator.vm.quirks = match platform {
+            Some(platform) => platform.quirks(),
+            None => info.map(|info| info.quirks).unwrap_or_default(),
+        };
+        self.emulator.vm.stack.set_depth(platform
+            .or_else(|| info.map(|info| info.platform))
+            .map_or(MAX_STACK_DEPTH, Platform::stack_depth));
+
+        self.emulator.scheduler.cycles_per_frame = get_variable(VARIABLES[1].0)
+            .and_then(|cycles| cycles.parse().ok())
+            .or_else(|| info.and_then(|info| info.cycles_per_frame))
+            .unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+
+        self.emulator.timing = get_variable(VARIABLES[2].0)
+            .and_then(|timing| TimingMode::parse(&timing))
+            .unwrap_or(TimingMode::Instructions);
+
+        self.palette = get_variable(VARIABLES[3].0)
+            .and_then(|name| Palette::from_name(&name))
+            .or_else(|| info.and_then(|info| info.palette))
+            .unwrap_or_default();
+    }
+
+    fn run(&mut self, callbacks: &Callbacks) {
+        let mut input = FrameInput::new();
+        if let Some(input_state) = callbacks.input_state {
+            for (button, key) in self.joypad.iter() {
+                if unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, *button) } != 0 {
+                    input.set_key(*key, true);
+                }
+            }
+        }
+
+        // the frontend drives timing, so exactly one frame per call
+        let output = self.emulator.run_frame(&input);
+        if output.error != self.error {
+            if let Some(error) = output.error {
+                log_error(callbacks, &error.to_string());
+            }
+            self.error = output.error;
+        }
+
+        self.palette.render_rgb(self.emulator.frame(), &mut self.video);
+        if let Some(video_refresh) = callbacks.video_refresh {
+            let pitch = DISPLAY_WIDTH * std::mem::size_of::<u32>();
+            unsafe { video_refresh(self.video.as_ptr() as *const c_void, DISPLAY_WIDTH as c_uint, DISPLAY_HEIGHT as c_uint, pitch) };
+        }
+
+        self.audio.clear();
+        if let Some(generator) = self.emulator.audio.as_mut() {
+            for sample in generator.take_samples() {
+                let sample = (sample * i16::MAX as f32) as i16;
+                self.audio.push(sample);
+                self.audio.push(sample);
+            }
+        }
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            unsafe { audio_sample_batch(self.audio.as_ptr(), self.audio.len() / 2) };
+        }
+    }
+}
+
+// same layout as the default gamepad mapping, with directions and face buttons moved
+// to the ROM's controls where the database knows them
+fn joypad_bindings(keys: &KeyBindings) -> Vec<(c_uint, u8)> {
+    let controls = [
+        (RETRO_DEVICE_ID_JOYPAD_UP, keys.up, 0x2),
+        (RETRO_DEVICE_ID_JOYPAD_DOWN, keys.down, 0x8),
+        (RETRO_DEVICE_ID_JOYPAD_LEFT, keys.left, 0x4),
+        (RETRO_DEVICE_ID_JOYPAD_RIGHT, keys.right, 0x6),
+        (RETRO_DEVICE_ID_JOYPAD_B, keys.a, 0x5),
+        (RETRO_DEVICE_ID_JOYPAD_A, keys.b, 0xA),
+        (RETRO_DEVICE_ID_JOYPAD_Y, None, 0x0),
+        (RETRO_DEVICE_ID_JOYPAD_X, None, 0xB),
+        (RETRO_DEVICE_ID_JOYPAD_SELECT, None, 0xE),
+        (RETRO_DEVICE_ID_JOYPAD_START, None, 0xF),
+    ];
+
+    controls
+        .iter()
+        .map(|(button, key, default)| (*button, key.unwrap_or(*default)))
+        .collect()
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    // copied out so callbacks can never deadlock on the lock
+    let callbacks = *CALLBACKS.lock().unwrap();
+    match callbacks.environment {
+        Some(environment) => unsafe { environment(cmd, data) },
+        None => false,
+    }
+}
+
+// through the frontend's log when it has one
+fn log_error(callbacks: &Callbacks, message: &str) {
+    let message = CString::new(message).unwrap_or_default();
+    match callbacks.log {
+        Some(log) => unsafe { log(RETRO_LOG_ERROR, c"rchip: %s\n".as_ptr(), message.as_ptr()) },
+        None => eprintln!("rchip: {}", message.to_string_lossy()),
+    }
+}
+
+fn get_variable(key: &CStr) -> Option<String> {
+    let mut var = RetroVariable {
+        key: key.as_ptr(),
+        value: ptr::null(),
+    };
+
+    if !environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut var as *mut RetroVariable as *mut c_void) || var.value.is_null() {
+        return None;
+    }
+
+    let value = unsafe { CStr::from_ptr(var.value) };
+    value.to_str().ok().map(String::from)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(cb);
+
+    let mut log = RetroLogCallback { log: None };
+    if environment(RETRO_ENVIRONMENT_GET_LOG_INTERFACE, &mut log as *mut RetroLogCallback as *mut c_void) {
+        CALLBACKS.lock().unwrap().log = log.log;
+    }
+
+    let mut variables: Vec<RetroVariable> = VARIABLES
+        .iter()
+        .map(|(key, value)| RetroVariable { key: key.as_ptr(), value: value.as_ptr() })
+        .collect();
+    variables.push(RetroVariable { key: ptr::null(), value: ptr::null() });
+    environment(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(cb);
+}
+
+// samples always go out a frame at a time through the batch callback
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: c"rchip".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"ch8|c8".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: DISPLAY_WIDTH as c_uint,
+            base_height: DISPLAY_HEIGHT as c_uint,
+            max_width: DISPLAY_WIDTH as c_uint,
+            max_height: DISPLAY_HEIGHT as c_uint,
+            aspect_ratio: DISPLAY_WIDTH as f32 / DISPLAY_HEIGHT as f32,
+        },
+        timing: RetroSystemTiming {
+            fps: FRAME_RATE as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        core.reset();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let mut updated = false;
+    environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void);
+
+    let callbacks = *CALLBACKS.lock().unwrap();
+    if let Some(input_poll) = callbacks.input_poll {
+        unsafe { input_poll() };
+    }
+
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        if updated {
+            core.apply_options();
+        }
+        core.run(&callbacks);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    STATE_SIZE
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = CORE.lock().unwrap();
+    let state = match core.as_ref().map(|core| core.emulator.save_state()) {
+        Some(Ok(state)) if state.len() <= size => state,
+        _ => return false,
+    };
+
+    ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let buf = std::slice::from_raw_parts(data as *const u8, size);
+    match CORE.lock().unwrap().as_mut() {
+        Some(core) => core.emulator.load_state(buf).is_ok(),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+        return false;
+    }
+
+    let rom = std::slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    match Core::new(rom) {
+        Ok(core) => {
+            *CORE.lock().unwrap() = Some(core);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match CORE.lock().unwrap().as_mut() {
+        Some(core) if id == RETRO_MEMORY_SYSTEM_RAM => core.emulator.vm.memory.as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match CORE.lock().unwrap().as_ref() {
+        Some(_) if id == RETRO_MEMORY_SYSTEM_RAM => crate::memory::MEM_SIZE,
+        _ => 0,
+    }
+}
@@ -1,27 +1,86 @@
-extern crate minifb;
-extern crate rodio;
-
 use std::process;
 use std::env;
+#[cfg(any(feature = "desktop", feature = "tui"))]
 use std::io;
+#[cfg(any(feature = "desktop", feature = "tui"))]
 use std::io::Write;
 use std::io::Read;
 use std::io::BufReader;
 use std::fs::File;
+#[cfg(any(feature = "desktop", feature = "tui"))]
 use std::collections::HashSet;
-use std::time::{Duration, Instant};
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use std::path::Path;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use std::fs;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use std::process::Command;
 
-use minifb::{Key, Window, WindowOptions};
-use rodio::{Sink, OutputStream, source::SineWave};
+#[cfg(feature = "desktop")]
+use minifb::{Key, KeyRepeat};
 
+use librchip::vm::START_ADDR;
+#[cfg(any(feature = "desktop", feature = "tui"))]
 use librchip::vm::VM;
-use librchip::display::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
-
-const CPU_CYCLE_RATE: u128 = 600;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::memory::MEM_SIZE;
+use librchip::decompile;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::error::VmError;
+use librchip::cfg::Cfg;
+use librchip::disasm;
+use librchip::emulator::{Emulator, FrameInput};
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::frontend::{self, InputSource, SystemClock};
+#[cfg(feature = "desktop")]
+use librchip::desktop::{MinifbFrontend, RodioAudio};
+#[cfg(feature = "desktop")]
+use librchip::audio::{AudioGenerator, Waveform, DEFAULT_FREQUENCY, DEFAULT_VOLUME};
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::config::Config;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::palette::Palette;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::romdb::RomInfo;
+use librchip::profiler::Profiler;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::filter::Filter;
+#[cfg(feature = "desktop")]
+use librchip::scaler::{Overlay, ScaleMode, Scaler, DEFAULT_SCALE};
+#[cfg(feature = "desktop")]
+use librchip::postfx::{self, PostFx};
+#[cfg(feature = "desktop")]
+use librchip::gamepad::{Gamepad, GamepadMapping};
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::watch::FileWatcher;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::quirks::Quirks;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::scheduler::Speed;
+#[cfg(any(feature = "desktop", feature = "tui"))]
+use librchip::stack::MAX_STACK_DEPTH;
+use librchip::timing::TimingMode;
+#[cfg(feature = "tui")]
+use librchip::terminal::{BeepStyle, Glyphs, TerminalFrontend};
 
-const PX_SCALING: usize = 10;  // pixel scaling factor
-const ON_PIXEL: u32 = 0x00FFFFFF; // white pixel
+#[cfg(feature = "desktop")]
+#[inline]
+fn print_hotkey_help() {
+    println!("HOTKEYS: ");
+    println!("  P        - pause / resume");
+    println!("  N        - advance one frame while paused");
+    println!("  Tab      - fast-forward while held");
+    println!("  - / =    - halve / double emulation speed");
+    println!("  [ / ]    - decrease / increase cycles per frame");
+    println!("  G        - cycle display filters");
+    println!("  Backspace - reset");
+    println!("  O        - load another ROM (asks for the path here)");
+    println!("  K        - save the state --restore goes back to");
+    println!("  H        - cycle post-processing presets");
+    println!("  F11      - toggle fullscreen");
+}
 
+#[cfg(any(feature = "desktop", feature = "tui"))]
 #[inline]
 fn print_debug_help() {
     println!("USAGE: ");
@@ -29,211 +88,1160 @@ fn print_debug_help() {
     println!("  b <addr> - add breakpoint at address <addr>");
     println!("  ni       - execute next instruction");
     println!("  p        - print current state of CHIP-8");
+    println!("  bb       - print the basic block being executed");
+    println!("  smc      - list the instructions written over so far (--selfmod)");
     println!("  help     - print list of commands available");
 }
 
 
-pub fn get_first_key(keys: Vec<Key>) -> Option<u8> {
-    for key in keys.iter() {
-        let exponent = match key {
-            Key::Key1 => Some(0x1),
-            Key::Key2 => Some(0x2),
-            Key::Key3 => Some(0x3),
-            Key::Key4 => Some(0xC),
-            Key::Q =>    Some(0x4),
-            Key::W =>    Some(0x5),
-            Key::E =>    Some(0x6),
-            Key::R =>    Some(0xD),
-            Key::A =>    Some(0x7),
-            Key::S =>    Some(0x8),
-            Key::D =>    Some(0x9),
-            Key::F =>    Some(0xE),
-            Key::Z =>    Some(0xA),
-            Key::X =>    Some(0x0),
-            Key::C =>    Some(0xB),
-            Key::V =>    Some(0xF),
-            _ => None
+#[cfg(any(feature = "desktop", feature = "tui"))]
+enum Hotkey {
+    TogglePause,
+    AdvanceFrame,
+    SlowDown,
+    SpeedUp,
+    FewerCycles,
+    MoreCycles,
+    NextFilter,
+    Reset,
+}
+
+// returns a message describing the change, if there is one worth showing
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn apply_hotkey(emulator: &mut Emulator, hotkey: Hotkey) -> Option<String> {
+    let scheduler = &mut emulator.scheduler;
+    match hotkey {
+        Hotkey::TogglePause => {
+            scheduler.toggle_pause();
+            Some(String::from(if scheduler.is_paused() { "Paused" } else { "Resumed" }))
+        }
+        Hotkey::AdvanceFrame => {
+            scheduler.advance_frame();
+            None
+        }
+        Hotkey::SlowDown | Hotkey::SpeedUp => match scheduler.speed {
+            Speed::Multiplier(multiplier) => {
+                let multiplier = if let Hotkey::SlowDown = hotkey { multiplier / 2.0 } else { multiplier * 2.0 };
+                scheduler.speed = Speed::Multiplier(multiplier);
+                Some(format!("Speed: {}x", multiplier))
+            }
+            Speed::Unthrottled => None,
+        },
+        Hotkey::FewerCycles | Hotkey::MoreCycles => {
+            if let Hotkey::MoreCycles = hotkey {
+                scheduler.cycles_per_frame += 1;
+            } else if scheduler.cycles_per_frame > 1 {
+                scheduler.cycles_per_frame -= 1;
+            }
+            Some(format!("Cycles per frame: {}", scheduler.cycles_per_frame))
+        }
+        Hotkey::Reset => {
+            emulator.reset();
+            Some(String::from("Reset"))
+        }
+        Hotkey::NextFilter => {
+            let filter = emulator.filter.mode().next();
+            emulator.filter.set_mode(filter);
+            Some(format!("Filter: {}", filter))
+        }
+    }
+}
+
+fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    let f = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut buf = Vec::new();
+    BufReader::new(f).read_to_end(&mut buf).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(buf)
+}
+
+// asks for the path of another ROM on stdin and swaps it in, returning a message and the
+// path. the current program keeps running if the new one can't be loaded. --watch follows
+// the new ROM
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn open_rom(emulator: &mut Emulator, overrides: &Overrides, watch: &mut Option<Watch>) -> Result<(String, String), String> {
+    print!("ROM to load: ");
+    let _ = io::stdout().flush();
+    let mut path = String::new();
+    let _ = io::stdin().read_line(&mut path); // blocks
+    let path = path.trim();
+    if path.is_empty() {
+        return Err(String::from("No ROM given"));
+    }
+
+    let buf = read_rom(path)?;
+    let rom_info = emulator.load_program(&buf).map_err(|e| format!("{}: {}", path, e))?;
+    overrides.apply(emulator);
+    if let Some(watch) = watch.as_mut() {
+        watch.retarget(path);
+    }
+    let message = match rom_info {
+        Some(info) => format!("Loaded {} by {}", info.title, info.author),
+        None => format!("Loaded {}", path),
+    };
+    Ok((message, path.to_string()))
+}
+
+#[cfg(any(feature = "desktop", feature = "tui"))]
+enum Reload {
+    Reset,           // start the new version from scratch
+    Keep,            // patch the new version in, keeping registers and memory
+    Restore(String), // go back to this save state, then patch the new version in
+}
+
+// --cpf, --stack, --quirk and --palette, which win over what the ROM database says and so
+// have to be applied again whenever a program is loaded
+#[cfg(any(feature = "desktop", feature = "tui"))]
+#[derive(Clone, Default)]
+struct Overrides {
+    cycles_per_frame: Option<u32>,
+    stack_depth: Option<usize>,
+    quirks: Vec<(String, bool)>,
+    palette: Option<Palette>,
+    default_palette: Option<Palette>, // from the config file, for ROMs without colors of their own
+}
+
+#[cfg(any(feature = "desktop", feature = "tui"))]
+impl Overrides {
+    fn apply(&self, emulator: &mut Emulator) {
+        if let Some(cycles) = self.cycles_per_frame {
+            emulator.scheduler.cycles_per_frame = cycles;
+        }
+        if let Some(depth) = self.stack_depth {
+            emulator.vm.stack.set_depth(depth);
+        }
+        // on top of the platform's quirks
+        for (name, enabled) in &self.quirks {
+            emulator.vm.quirks.set(name, *enabled);
+        }
+    }
+
+    // --palette, then the ROM's own colors, then the config file
+    fn palette(&self, rom_info: Option<&RomInfo>) -> Option<Palette> {
+        self.palette
+            .or_else(|| rom_info.and_then(|info| info.palette))
+            .or(self.default_palette)
+    }
+}
+
+// --watch: picks up edits to the ROM, and rebuilds it when its source changes
+#[cfg(any(feature = "desktop", feature = "tui"))]
+struct Watch {
+    rom: String,
+    reload: Reload,
+    build: Option<(String, String)>, // source file and the command that assembles it
+    overrides: Overrides,
+    watcher: FileWatcher,
+}
+
+#[cfg(any(feature = "desktop", feature = "tui"))]
+impl Watch {
+    fn new(rom: &str, reload: Reload, build: Option<(String, String)>, overrides: Overrides) -> Watch {
+        let mut paths = vec![rom.to_string()];
+        if let Some((source, _)) = &build {
+            paths.push(source.clone());
+        }
+
+        Watch {
+            rom: rom.to_string(),
+            reload,
+            build,
+            overrides,
+            watcher: FileWatcher::new(paths),
+        }
+    }
+
+    // watches `rom` instead. the --source and --build, and a --restore state, were for the
+    // old ROM so they're dropped
+    fn retarget(&mut self, rom: &str) {
+        if let Reload::Restore(_) = self.reload {
+            self.reload = Reload::Reset;
+        }
+        *self = Watch::new(rom, std::mem::replace(&mut self.reload, Reload::Reset), None, self.overrides.clone());
+    }
+
+    // call every frame, returns a message when something happened
+    fn poll(&mut self, emulator: &mut Emulator) -> Option<String> {
+        let changed = self.watcher.poll();
+        if changed.is_empty() {
+            return None;
+        }
+
+        if let Some((source, command)) = &self.build {
+            if changed.iter().any(|path| path == Path::new(source)) {
+                // the rebuilt ROM is picked up as a change of its own
+                return Some(match Command::new("sh").arg("-c").arg(command).output() {
+                    Ok(output) if output.status.success() => format!("Rebuilt {}", self.rom),
+                    Ok(output) => {
+                        let stderr = String::from_utf8_lossy(&output.stderr);
+                        format!("Build failed: {}", stderr.lines().next().unwrap_or("no output"))
+                    }
+                    Err(e) => format!("Build failed: {}", e),
+                });
+            }
+        }
+
+        Some(self.reload(emulator).unwrap_or_else(|e| format!("Reload failed: {}", e)))
+    }
+
+    fn reload(&self, emulator: &mut Emulator) -> Result<String, String> {
+        let buf = read_rom(&self.rom)?;
+        match &self.reload {
+            Reload::Reset => {
+                emulator.load_program(&buf).map_err(|e| e.to_string())?;
+                self.overrides.apply(emulator);
+            }
+            Reload::Keep => {
+                emulator.vm.patch_rom(&buf).map_err(|e| e.to_string())?;
+            }
+            Reload::Restore(path) => {
+                let state = fs::read(path).map_err(|e| format!("{}: {} (K saves one)", path, e))?;
+                emulator.load_state(&state).map_err(|e| format!("{}: {}", path, e))?;
+                emulator.vm.patch_rom(&buf).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(format!("Reloaded {}", self.rom))
+    }
+
+    // K: remember where to come back to on every reload
+    fn save_state(&self, emulator: &Emulator) -> Option<String> {
+        let path = match &self.reload {
+            Reload::Restore(path) => path,
+            _ => return None,
         };
+        let result = emulator.save_state().map_err(|e| e.to_string())
+            .and_then(|state| fs::write(path, state).map_err(|e| e.to_string()));
+        Some(match result {
+            Ok(()) => format!("Saved state to {}", path),
+            Err(e) => format!("Could not save state to {}: {}", path, e),
+        })
+    }
+}
+
+#[cfg(any(feature = "desktop", feature = "tui"))]
+enum DebugAction {
+    Run,  // leave debug mode until the next breakpoint
+    Step, // execute one instruction and prompt again
+}
+
+// prompts for debugger commands until execution should continue
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn run_debugger(chip: &VM, breakpoints: &mut HashSet<u16>) -> DebugAction {
+    let stdin = io::stdin(); 
+    let input = &mut String::new();
+
+    loop {
+        print!(">> ");
+        let _ = io::stdout().flush();
+        input.clear();
+        let _ = stdin.read_line(input); // blocks
+        let commands: Vec<&str> = input.split_whitespace().collect();
+
+        if commands.is_empty() {
+            print_debug_help();
+            continue;
+        }
+
+        match commands[0] {
+            "r" =>  {
+                // run until we hit a breakpoint, or run until end
+                return DebugAction::Run;
+            }
+            "b" => {
+                if commands.len() == 1 {
+                    eprintln!("No breakpoint specified.");
+                    eprintln!("Usage: b <address>");
+                    continue;
+                }
 
-        
-        if !exponent.is_none() {
-            return exponent;
+                let breakpoint: u16 = match u16::from_str_radix(commands[1], 16) {
+                    Ok(addr) => addr,
+                    Err(_) => {
+                        eprintln!("Badly formatted hex address.");
+                        eprintln!("Enter a valid hex address without leading '0x'");
+                        continue;
+                    }
+                };
+                // set a breakpoint at <breakpoint> 
+                println!("Setting break point at {:#x}", breakpoint);
+                breakpoints.insert(breakpoint);
+            }
+            "p" => {
+                // print state of VM
+                println!("{}", chip);
+            }
+            "bb" => {
+                // the analysis starts from wherever execution is, in case it got there through BNNN
+                let cfg = Cfg::build(&chip.memory, &[START_ADDR as u16, chip.pc]);
+                match cfg.block_containing(chip.pc) {
+                    Some(block) => {
+                        println!("{:03x}:", block.start);
+                        for line in disasm::disassemble_block(block, &chip.quirks) {
+                            println!("{}", line);
+                        }
+                    }
+                    None => eprintln!("No block at {:#x}", chip.pc),
+                }
+            }
+            "smc" => match chip.selfmod.as_ref() {
+                Some(selfmod) if selfmod.is_empty() => println!("No self-modifying code so far"),
+                Some(selfmod) => print!("{}", selfmod.summary()),
+                None => eprintln!("Self-modifying code is only tracked with --selfmod"),
+            },
+            "ni" => {
+                // next instruction, if there is a whole one left in memory
+                if chip.pc as usize + 1 >= MEM_SIZE {
+                    eprintln!("Error: {}", VmError::PcOutOfRange(chip.pc));
+                    continue;
+                }
+                println!("{:#x}\topcode={:#x}", chip.pc, chip.memory.get_instr(chip.pc));
+                return DebugAction::Step;
+            }
+            "help" => {
+                print_debug_help();
+            }
+            _ => { }
         }
     }
+}
 
-    None
+
+// `rchip cfg <file_path>`: static analysis of a ROM, written to stdout
+fn run_cfg(args: &[String]) {
+    let usage = "USAGE: rchip cfg <file_path> [--calls | --listing]";
+    let file_path = args.first().unwrap_or_else(|| {
+        eprintln!("Error: Missing path to CHIP-8 program to analyse");
+        eprintln!("{}", usage);
+        process::exit(1);
+    });
+    let output = match args.get(1).map(String::as_str) {
+        None => "cfg",
+        Some("--calls") => "calls",
+        Some("--listing") => "listing",
+        Some(opt) => {
+            eprintln!("Error: Unknown option {}", opt);
+            eprintln!("{}", usage);
+            process::exit(1);
+        }
+    };
+
+    let buf = read_rom(file_path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    // loaded like it would be run, so known ROMs get their quirks
+    let mut emulator = Emulator::new();
+    if let Err(e) = emulator.load_program(&buf) {
+        eprintln!("Error: {}: {}", file_path, e);
+        process::exit(1);
+    }
+
+    let vm = &emulator.vm;
+    let cfg = Cfg::build(&vm.memory, &[START_ADDR as u16]);
+    match output {
+        "calls" => print!("{}", cfg.call_graph_dot()),
+        "listing" => print!("{}", disasm::disassemble_cfg(&cfg, &vm.quirks)),
+        _ => print!("{}", cfg.to_dot(&vm.quirks)),
+    }
+}
+
+// `rchip decompile <file_path>`: Octo source for a ROM, written to stdout
+fn run_decompile(args: &[String]) {
+    let file_path = match args {
+        [file_path] => file_path,
+        _ => {
+            eprintln!("USAGE: rchip decompile <file_path>");
+            process::exit(1);
+        }
+    };
+    let buf = read_rom(file_path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    match decompile::decompile(&buf) {
+        Ok(source) => print!("{}", source),
+        Err(e) => {
+            eprintln!("Error: {}: {}", file_path, e);
+            process::exit(1);
+        }
+    }
+}
+
+// --coverage and --profile reports and the --selfmod summary, once emulation is over
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn write_reports(emulator: &Emulator, coverage: Option<&str>, profile: Option<&str>) {
+    if let Some(path) = coverage {
+        write_coverage(&emulator.vm, path);
+    }
+    if let Some(path) = profile {
+        write_profile(emulator, path);
+    }
+    print_selfmod_summary(&emulator.vm);
+}
+
+// what strict mode and --selfmod found since the last call
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn print_warnings(vm: &mut VM) {
+    for diagnostic in vm.take_diagnostics() {
+        eprintln!("Warning: {}", diagnostic);
+    }
+    for patch in vm.take_patches() {
+        println!("Self-modifying code: {}", patch);
+    }
+}
+
+// everything --selfmod found, when rchip exits
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn print_selfmod_summary(vm: &VM) {
+    match vm.selfmod.as_ref() {
+        Some(selfmod) if selfmod.is_empty() => println!("No self-modifying code found"),
+        Some(selfmod) => print!("Self-modifying code:\n{}", selfmod.summary()),
+        None => (),
+    }
+}
+
+// the report for --coverage, as JSON if the file name ends in .json
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn write_coverage(vm: &VM, path: &str) {
+    let coverage = match vm.coverage.as_ref() {
+        Some(coverage) => coverage,
+        None => return,
+    };
+    let report = if path.ends_with(".json") { coverage.to_json(&vm.memory) } else { coverage.listing(&vm.memory, &vm.quirks) };
+    match fs::write(path, report) {
+        Ok(()) => println!("Coverage: {}, report written to {}", coverage.summary(&vm.memory), path),
+        Err(e) => eprintln!("Error: Could not write {}: {}", path, e),
+    }
+}
+
+// loads a ROM and runs it for `frames` frames without any keys pressed, or until it fails
+fn run_headless(emulator: &mut Emulator, file_path: &str, frames: u32) {
+    let buf = read_rom(file_path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    emulator.load_program(&buf).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", file_path, e);
+        process::exit(1);
+    });
+
+    for _ in 0..frames {
+        if let Some(e) = emulator.run_frame(&FrameInput::new()).error {
+            eprintln!("Error: {}", e);
+            break;
+        }
+    }
+}
+
+// `rchip coverage <file_path>`: runs a ROM headless, without any keys pressed, and
+// prints its coverage report to stdout
+fn run_coverage(args: &[String]) {
+    let usage = "USAGE: rchip coverage <file_path> [--frames <n>] [--json]";
+    let file_path = args.first().unwrap_or_else(|| {
+        eprintln!("Error: Missing path to CHIP-8 program to run");
+        eprintln!("{}", usage);
+        process::exit(1);
+    });
+    let mut frames = 600;
+    let mut json = false;
+    let mut opts = args[1..].iter();
+    while let Some(opt) = opts.next() {
+        match opt.as_str() {
+            "--frames" => {
+                frames = opts.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| {
+                    eprintln!("Error: --frames expects a number of frames to run");
+                    process::exit(1);
+                });
+            }
+            "--json" => json = true,
+            _ => {
+                eprintln!("Error: Unknown option {}", opt);
+                eprintln!("{}", usage);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut emulator = Emulator::new();
+    emulator.vm.set_coverage(true);
+    run_headless(&mut emulator, file_path, frames);
+
+    let vm = &emulator.vm;
+    if let Some(coverage) = vm.coverage.as_ref() {
+        print!("{}", if json { coverage.to_json(&vm.memory) } else { coverage.listing(&vm.memory, &vm.quirks) });
+    }
+}
+
+// the report for --profile, as collapsed stacks if the file name ends in .folded
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn write_profile(emulator: &Emulator, path: &str) {
+    let profiler = match emulator.profiler.as_ref() {
+        Some(profiler) => profiler,
+        None => return,
+    };
+    let report = if path.ends_with(".folded") {
+        profiler.collapsed(emulator.timing == TimingMode::CosmacVip)
+    } else {
+        profiler.report(&emulator.vm.memory)
+    };
+    match fs::write(path, report) {
+        Ok(()) => println!("Profile of {} frames written to {}", profiler.frames, path),
+        Err(e) => eprintln!("Error: Could not write {}: {}", path, e),
+    }
 }
 
+// `rchip profile <file_path>`: runs a ROM headless like `rchip coverage` and prints where
+// it spent its time
+fn run_profile(args: &[String]) {
+    let usage = "USAGE: rchip profile <file_path> [--frames <n>] [--timing <instructions|vip>] [--collapsed]";
+    let file_path = args.first().unwrap_or_else(|| {
+        eprintln!("Error: Missing path to CHIP-8 program to run");
+        eprintln!("{}", usage);
+        process::exit(1);
+    });
+    let mut frames = 600;
+    let mut timing = TimingMode::Instructions;
+    let mut collapsed = false;
+    let mut opts = args[1..].iter();
+    while let Some(opt) = opts.next() {
+        match opt.as_str() {
+            "--frames" => {
+                frames = opts.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| {
+                    eprintln!("Error: --frames expects a number of frames to run");
+                    process::exit(1);
+                });
+            }
+            "--timing" => {
+                timing = opts.next().and_then(|t| TimingMode::parse(t)).unwrap_or_else(|| {
+                    eprintln!("Error: --timing expects instructions or vip");
+                    process::exit(1);
+                });
+            }
+            "--collapsed" => collapsed = true,
+            _ => {
+                eprintln!("Error: Unknown option {}", opt);
+                eprintln!("{}", usage);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut emulator = Emulator::new();
+    emulator.timing = timing;
+    emulator.profiler = Some(Profiler::new());
+    run_headless(&mut emulator, file_path, frames);
+
+    if let Some(profiler) = emulator.profiler.as_ref() {
+        if collapsed {
+            print!("{}", profiler.collapsed(timing == TimingMode::CosmacVip));
+        } else {
+            print!("{}", profiler.report(&emulator.vm.memory));
+        }
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
+    match args.get(1).map(String::as_str) {
+        Some("cfg") => return run_cfg(&args[2..]),
+        Some("decompile") => return run_decompile(&args[2..]),
+        Some("coverage") => return run_coverage(&args[2..]),
+        Some("profile") => return run_profile(&args[2..]),
+        _ => (),
+    }
+
+    #[cfg(any(feature = "desktop", feature = "tui"))]
+    run_emulator(&args);
+
+    #[cfg(not(any(feature = "desktop", feature = "tui")))]
+    {
+        eprintln!("Error: rchip was built without a frontend, only the cfg, decompile, coverage and profile subcommands are available");
+        process::exit(1);
+    }
+}
+
+// everything but the subcommands: runs the program in a window or, with --tui, the terminal
+#[cfg(any(feature = "desktop", feature = "tui"))]
+fn run_emulator(args: &[String]) {
     if args.len() < 2 {
         // does not contain path to .ch8 program  
         eprintln!("Error: Missing path to CHIP-8 program to emulate");
-        eprintln!("USAGE: cargo run <file_path>");
+        eprintln!("USAGE: cargo run <file_path> [-d] [--pad <mapping_file>] [--cpf <cycles>] [--speed <x|max>] [--ff <x|max>] [--timing <instructions|vip>] [--waveform <square|sine>] [--frequency <hz>] [--volume <0-1>] [--palette <theme|colors>] [--filter <off|blend|phosphor[:frames]|stable>] [--stack <1-16>] [--strict] [--quirk <name>[=off]] [--selfmod] [--coverage <report_file>] [--profile <report_file>] [--scale <n>] [--scaling <integer|aspect|stretch>] [--overlay <none|grid|scanlines>] [--fullscreen] [--fullscreen-size <WxH>] [--postfx <off|scanlines|crt|glow>] [--watch [--keep-state | --restore <state_file>] [--source <file.8o> --build <command>]] [--tui] [--glyphs <blocks|braille>] [--beep <bell|flash>] [--panel]");
         process::exit(1);
     }
 
     let mut debug = false;
+    let mut overrides = Overrides::default();
+    let mut strict = false;
+    let mut selfmod = false;
+    let mut coverage: Option<String> = None;
+    let mut profile: Option<String> = None;
+    let mut speed = Speed::Multiplier(1.0);
+    let mut fast_forward_speed = Speed::Unthrottled;
+    let mut timing = TimingMode::Instructions;
+    let mut filter = Filter::Off;
+    let mut watch = false;
+    let mut reload = Reload::Reset;
+    let mut source: Option<String> = None;
+    let mut build: Option<String> = None;
+    #[cfg(feature = "desktop")]
+    let mut desktop = DesktopOptions {
+        pad_path: None,
+        waveform: Waveform::Square,
+        frequency: DEFAULT_FREQUENCY,
+        volume: DEFAULT_VOLUME,
+        scale: DEFAULT_SCALE,
+        scaler: Scaler::new(),
+        fullscreen: false,
+        fullscreen_size: None,
+        preset: 0,
+    };
+    #[cfg(feature = "tui")]
+    let mut tui = TuiOptions { enabled: false, glyphs: Glyphs::HalfBlock, beep: BeepStyle::Bell, panel: false };
 
-    if args.len() == 3 && args[2] == "-d" {
-        // enter debug mode
-        debug = true;
+    let mut opts = args[2..].iter();
+    while let Some(opt) = opts.next() {
+        match opt.as_str() {
+            "-d" => debug = true,
+            #[cfg(feature = "desktop")]
+            "--pad" => desktop.pad_path = opts.next().cloned(),
+            "--cpf" => {
+                overrides.cycles_per_frame = opts.next().and_then(|n| n.parse().ok()).filter(|n| *n > 0);
+                if overrides.cycles_per_frame.is_none() {
+                    eprintln!("Error: --cpf expects a positive number of cycles per frame");
+                    process::exit(1);
+                }
+            }
+            "--stack" => {
+                overrides.stack_depth = opts.next().and_then(|n| n.parse().ok()).filter(|n| (1..=MAX_STACK_DEPTH).contains(n));
+                if overrides.stack_depth.is_none() {
+                    eprintln!("Error: --stack expects a call stack depth from 1 to {}", MAX_STACK_DEPTH);
+                    process::exit(1);
+                }
+            }
+            "--strict" => strict = true,
+            "--selfmod" => selfmod = true,
+            "--coverage" | "--profile" => {
+                let path = opts.next().cloned().unwrap_or_else(|| {
+                    eprintln!("Error: {} expects a file to write the report to", opt);
+                    process::exit(1);
+                });
+                if opt == "--coverage" { coverage = Some(path); } else { profile = Some(path); }
+            }
+            "--quirk" => {
+                let quirk = opts.next().map(|q| match q.split_once('=') {
+                    Some((name, value)) => (name, value),
+                    None => (q.as_str(), "on"),
+                });
+                match quirk {
+                    Some((name, value)) if Quirks::default().get(name).is_some() && (value == "on" || value == "off") => {
+                        overrides.quirks.push((name.to_string(), value == "on"));
+                    }
+                    _ => {
+                        eprintln!("Error: --quirk expects a quirk name, optionally followed by =on or =off");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--speed" | "--ff" => {
+                let parsed = opts.next().and_then(|s| Speed::parse(s)).unwrap_or_else(|| {
+                    eprintln!("Error: {} expects a multiplier such as 2 or 0.5, or max", opt);
+                    process::exit(1);
+                });
+                if opt == "--speed" { speed = parsed; } else { fast_forward_speed = parsed; }
+            }
+            "--timing" => {
+                timing = opts.next().and_then(|t| TimingMode::parse(t)).unwrap_or_else(|| {
+                    eprintln!("Error: --timing expects instructions or vip");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--waveform" => {
+                desktop.waveform = opts.next().and_then(|w| Waveform::parse(w)).unwrap_or_else(|| {
+                    eprintln!("Error: --waveform expects square or sine");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--frequency" => {
+                desktop.frequency = opts.next().and_then(|f| f.parse().ok()).filter(|f: &f32| *f > 0.0).unwrap_or_else(|| {
+                    eprintln!("Error: --frequency expects a positive frequency in Hz");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--volume" => {
+                desktop.volume = opts.next().and_then(|v| v.parse().ok()).filter(|v: &f32| (0.0..=1.0).contains(v)).unwrap_or_else(|| {
+                    eprintln!("Error: --volume expects a value from 0 to 1");
+                    process::exit(1);
+                });
+            }
+            "--palette" => {
+                overrides.palette = opts.next().and_then(|p| Palette::parse(p));
+                if overrides.palette.is_none() {
+                    eprintln!("Error: --palette expects classic, amber, lcd, octo or hex colors such as #000000,#ffffff");
+                    process::exit(1);
+                }
+            }
+            "--filter" => {
+                filter = opts.next().and_then(|f| Filter::parse(f)).unwrap_or_else(|| {
+                    eprintln!("Error: --filter expects off, blend, phosphor, phosphor:<frames> or stable");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--scale" => {
+                desktop.scale = opts.next().and_then(|s| s.parse().ok()).filter(|s| *s > 0).unwrap_or_else(|| {
+                    eprintln!("Error: --scale expects a positive number of window pixels per pixel");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--scaling" => {
+                desktop.scaler.mode = opts.next().and_then(|m| ScaleMode::parse(m)).unwrap_or_else(|| {
+                    eprintln!("Error: --scaling expects integer, aspect or stretch");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--overlay" => {
+                desktop.scaler.overlay = opts.next().and_then(|o| Overlay::parse(o)).unwrap_or_else(|| {
+                    eprintln!("Error: --overlay expects none, grid or scanlines");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "desktop")]
+            "--fullscreen" => desktop.fullscreen = true,
+            #[cfg(feature = "desktop")]
+            "--fullscreen-size" => {
+                desktop.fullscreen_size = opts.next().and_then(|s| librchip::config::parse_size(s));
+                if desktop.fullscreen_size.is_none() {
+                    eprintln!("Error: --fullscreen-size expects the monitor's resolution, e.g. 2560x1440");
+                    process::exit(1);
+                }
+            }
+            "--watch" => watch = true,
+            "--keep-state" => reload = Reload::Keep,
+            "--restore" => {
+                reload = Reload::Restore(opts.next().cloned().unwrap_or_else(|| {
+                    eprintln!("Error: --restore expects a save state file");
+                    process::exit(1);
+                }));
+            }
+            "--source" | "--build" => {
+                let value = opts.next().cloned().unwrap_or_else(|| {
+                    eprintln!("Error: {} expects a {}", opt, if opt == "--source" { "source file" } else { "command" });
+                    process::exit(1);
+                });
+                if opt == "--source" { source = Some(value); } else { build = Some(value); }
+            }
+            #[cfg(feature = "desktop")]
+            "--postfx" => {
+                desktop.preset = opts.next().and_then(|p| postfx::PRESETS.iter().position(|name| name == p)).unwrap_or_else(|| {
+                    eprintln!("Error: --postfx expects one of {}", postfx::PRESETS.join(", "));
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "tui")]
+            "--tui" => tui.enabled = true,
+            #[cfg(feature = "tui")]
+            "--panel" => tui.panel = true,
+            #[cfg(feature = "tui")]
+            "--glyphs" => {
+                tui.glyphs = opts.next().and_then(|g| Glyphs::parse(g)).unwrap_or_else(|| {
+                    eprintln!("Error: --glyphs expects blocks or braille");
+                    process::exit(1);
+                });
+            }
+            #[cfg(feature = "tui")]
+            "--beep" => {
+                tui.beep = opts.next().and_then(|b| BeepStyle::parse(b)).unwrap_or_else(|| {
+                    eprintln!("Error: --beep expects bell or flash");
+                    process::exit(1);
+                });
+            }
+            _ => {
+                eprintln!("Error: Unknown option {}", opt);
+                process::exit(1);
+            }
+        }
+    }
 
+    if debug {
+        // enter debug mode
         println!("Starting program in debug mode...");
         print_debug_help();
     }
 
-    let file_path = &args[1];
-    let f = File::open(file_path).expect("Error: File not found");
-    let mut reader = BufReader::new(f);
-    let mut buf = Vec::new();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Error: Bad config file: {}", e);
+        process::exit(1);
+    });
+    overrides.default_palette = config.palette;
+    #[cfg(feature = "desktop")]
+    if desktop.fullscreen_size.is_none() {
+        desktop.fullscreen_size = config.fullscreen_size;
+    }
 
-    // TODO: add error
-    reader.read_to_end(&mut buf).unwrap(); 
+    let file_path = &args[1];
 
-    let mut chip = VM::new();
-    chip.load_program(&buf);
+    let build = match (source, build) {
+        (Some(source), Some(command)) => Some((source, command)),
+        (None, None) => None,
+        _ => {
+            eprintln!("Error: --source and --build go together");
+            process::exit(1);
+        }
+    };
+    let mut watch = if watch { Some(Watch::new(file_path, reload, build, overrides.clone())) } else { None };
 
-    let win_width = DISPLAY_WIDTH * PX_SCALING;
-    let win_height = DISPLAY_HEIGHT * PX_SCALING;
-    let mut buffer: Vec<u32> = vec![0; win_width * win_height];
+    let buf = read_rom(file_path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
 
-    let mut window = Window::new (
-        "CHIP-8",
-        win_width,
-        win_height,
-        WindowOptions::default(),
-    ).unwrap_or_else(|e| {
-        panic!("{}", e);
+    // the emulator applies whatever the ROM database knows about this program
+    let mut emulator = Emulator::new();
+    emulator.vm.set_strict(strict);
+    emulator.vm.set_coverage(coverage.is_some());
+    emulator.vm.set_selfmod(selfmod);
+    if profile.is_some() {
+        emulator.profiler = Some(Profiler::new());
+    }
+    let rom_info = emulator.load_program(&buf).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", file_path, e);
+        process::exit(1);
     });
+    if let Some(info) = rom_info {
+        println!("Loaded {} by {}", info.title, info.author);
+    }
+
+    emulator.timing = timing;
+    emulator.filter.set_mode(filter);
+    overrides.apply(&mut emulator);
+    let scheduler = &mut emulator.scheduler;
+    scheduler.speed = speed;
+    scheduler.fast_forward_speed = fast_forward_speed;
 
-    // 60fps
-    window.limit_update_rate(Some(Duration::from_millis(1000/60)));
+    #[cfg(feature = "tui")]
+    if tui.enabled {
+        run_terminal(&mut emulator, &tui, &overrides, debug, &mut watch);
+        write_reports(&emulator, coverage.as_deref(), profile.as_deref());
+        return;
+    }
+
+    #[cfg(feature = "desktop")]
+    {
+        run_desktop(&mut emulator, file_path, &desktop, &overrides, debug, &mut watch);
+        write_reports(&emulator, coverage.as_deref(), profile.as_deref());
+    }
 
-    let cur_t = Instant::now();
-    let mut finished_cycles = 0;
+    #[cfg(not(feature = "desktop"))]
+    {
+        let _ = (debug, watch, overrides);
+        eprintln!("Error: rchip was built without the desktop frontend, run it with --tui");
+        process::exit(1);
+    }
+}
 
-    let mut redraw = false;
 
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    sink.append(SineWave::new(356.0)); // create a beep
-    sink.pause();
+#[cfg(feature = "desktop")]
+struct DesktopOptions {
+    pad_path: Option<String>,
+    waveform: Waveform,
+    frequency: f32,
+    volume: f32,
+    scale: usize,
+    scaler: Scaler,
+    fullscreen: bool,
+    fullscreen_size: Option<(usize, usize)>, // --fullscreen-size, then the config file
+    preset: usize, // index into postfx::PRESETS
+}
 
-    // debugging stuff
-    let mut breakpoints: HashSet<u16> = HashSet::new();
+// the mapping for the ROM at `rom_path`: an explicit --pad file wins over a mapping
+// stored next to the ROM, which wins over the ROM database's keys
+#[cfg(feature = "desktop")]
+fn gamepad_mapping(rom_path: &str, pad_path: Option<&str>, rom_info: Option<&RomInfo>) -> Result<GamepadMapping, String> {
+    let base_mapping = match rom_info {
+        Some(info) => GamepadMapping::from_keys(&info.keys),
+        None => GamepadMapping::default(),
+    };
+    let rom_pad_path = GamepadMapping::rom_mapping_path(Path::new(rom_path));
+    match pad_path {
+        Some(path) => GamepadMapping::load(Path::new(path), base_mapping),
+        None if rom_pad_path.exists() => GamepadMapping::load(&rom_pad_path, base_mapping),
+        None => Ok(base_mapping),
+    }
+}
 
-    // start fetching
-    while window.is_open() && !window.is_key_down(Key::Escape) {
+#[cfg(feature = "desktop")]
+fn window_title(rom_info: Option<&RomInfo>) -> String {
+    match rom_info {
+        Some(info) => format!("CHIP-8 - {}", info.title),
+        None => String::from("CHIP-8"),
+    }
+}
 
-        let expected_cycles: u128 = cur_t.elapsed().as_millis() / (1000 / CPU_CYCLE_RATE); 
+#[cfg(feature = "desktop")]
+fn run_desktop(emulator: &mut Emulator, file_path: &str, options: &DesktopOptions, overrides: &Overrides, mut debug: bool, watch: &mut Option<Watch>) {
+    let rom_info = emulator.rom_info();
+    let mut preset = options.preset;
 
-        for _ in 0..(expected_cycles - finished_cycles) {
-            // check if current pc is in breakpoints to pause at
-            if breakpoints.contains(&chip.pc) {
-                println!("Hit a breakpoint at {:#x}", chip.pc);
-                debug = true;
-            }
+    let mapping = gamepad_mapping(file_path, options.pad_path.as_deref(), rom_info).unwrap_or_else(|e| {
+        eprintln!("Error: Bad gamepad mapping: {}", e);
+        process::exit(1);
+    });
 
-            if debug {
-                let stdin = io::stdin(); 
-                let input = &mut String::new();
+    let (width, height) = emulator.resolution();
+    let mut window = MinifbFrontend::new(&window_title(rom_info), options.scale, width, height);
+    window.gamepad = Some(Gamepad::new(mapping));
+    window.palette = overrides.palette(rom_info).unwrap_or_default();
+    window.scaler = options.scaler;
+    window.postfx = PostFx::preset(postfx::PRESETS[preset]).unwrap_or_default();
+    if let Some(size) = options.fullscreen_size {
+        window.fullscreen_size = size;
+    }
+    if options.fullscreen {
+        window.toggle_fullscreen();
+    }
+    let mut audio = RodioAudio::new();
+    let mut generator = AudioGenerator::new(audio.sample_rate());
+    generator.waveform = options.waveform;
+    generator.frequency = options.frequency;
+    generator.volume = options.volume;
+    emulator.audio = Some(generator);
+    let mut clock = SystemClock::new();
 
-                loop {
-                    print!(">> ");
-                    let _ = io::stdout().flush();
-                    input.clear();
-                    let _ = stdin.read_line(input); // blocks
-                    let commands: Vec<&str> = input.split_whitespace().collect();
+    print_hotkey_help();
+    frontend::present(emulator, &mut window);
 
-                    if commands.len() == 0 {
-                        print_debug_help();
-                        continue;
+    // start fetching
+    while !window.should_quit() {
+        while debug {
+            match run_debugger(&emulator.vm, &mut emulator.breakpoints) {
+                DebugAction::Run => {
+                    debug = false;
+                    emulator.scheduler.set_paused(false);
+                }
+                DebugAction::Step => {
+                    let input = window.poll();
+                    let output = emulator.step(&input);
+                    if let Some(e) = output.error {
+                        eprintln!("Error: {}", e);
                     }
-
-                    match commands[0] {
-                        "r" =>  {
-                            // run until we hit a breakpoint, or run until end
-                            debug = false;
-                            break;
-                        }
-                        "b" => {
-                            let breakpoint: u16;
-
-                            if commands.len() == 1 {
-                                eprintln!("No breakpoint specified.");
-                                eprintln!("Usage: b <address>");
-                            }
-
-                            match u16::from_str_radix(commands[1], 16) {
-                                Ok(addr) => {
-                                    breakpoint = addr;
-                                }
-                                Err(_) => {
-                                    eprintln!("Badly formatted hex address.");
-                                    eprintln!("Enter a valid hex address without leading '0x'");
-                                    continue;
-                                }
-                            }
-                            // set a breakpoint at <breakpoint> 
-                            println!("Setting break point at {:#x}", breakpoint);
-                            breakpoints.insert(breakpoint);
-                        }
-                        "p" => {
-                            // print state of VM
-                            println!("{}", chip);
-                        }
-                        "ni" => {
-                            // next instruction
-                            println!("{:#x}\topcode={:#x}", chip.pc, chip.memory.get_instr(chip.pc));
-                            break;
-                        }
-                        "help" => {
-                            print_debug_help();
-                        }
-                        _ => { }
+                    print_warnings(&mut emulator.vm);
+                    if output.redraw {
+                        frontend::present(emulator, &mut window);
                     }
                 }
             }
+        }
 
-            chip.reset_keys();
-            chip.set_key(get_first_key(window.get_keys()), true);
-
-            chip.emulate_cycle();
-            finished_cycles += 1;
-
-            if chip.redraw {
-                redraw = true;
+        let keys = &window.window;
+        let hotkeys = [
+            (Key::P, KeyRepeat::No, Hotkey::TogglePause),
+            (Key::N, KeyRepeat::Yes, Hotkey::AdvanceFrame),
+            (Key::Minus, KeyRepeat::No, Hotkey::SlowDown),
+            (Key::Equal, KeyRepeat::No, Hotkey::SpeedUp),
+            (Key::LeftBracket, KeyRepeat::Yes, Hotkey::FewerCycles),
+            (Key::RightBracket, KeyRepeat::Yes, Hotkey::MoreCycles),
+            (Key::G, KeyRepeat::No, Hotkey::NextFilter),
+            (Key::Backspace, KeyRepeat::No, Hotkey::Reset),
+        ];
+        if keys.is_key_pressed(Key::K, KeyRepeat::No) {
+            if let Some(message) = watch.as_ref().and_then(|watch| watch.save_state(emulator)) {
+                println!("{}", message);
+            }
+        }
+        for (key, repeat, hotkey) in hotkeys {
+            if keys.is_key_pressed(key, repeat) {
+                if let Some(message) = apply_hotkey(emulator, hotkey) {
+                    println!("{}", message);
+                }
+            }
+        }
+        emulator.scheduler.set_fast_forward(keys.is_key_down(Key::Tab));
+        if window.window.is_key_pressed(Key::H, KeyRepeat::No) {
+            preset = (preset + 1) % postfx::PRESETS.len();
+            window.postfx = PostFx::preset(postfx::PRESETS[preset]).unwrap_or_default();
+            window.redraw();
+            println!("Post-processing: {}", postfx::PRESETS[preset]);
+        }
+        if window.window.is_key_pressed(Key::F11, KeyRepeat::No) {
+            window.toggle_fullscreen();
+        }
+        if window.window.is_key_pressed(Key::O, KeyRepeat::No) {
+            match open_rom(emulator, overrides, watch) {
+                Ok((message, path)) => {
+                    println!("{}", message);
+                    // the same way as for the first ROM
+                    let rom_info = emulator.rom_info();
+                    window.set_title(&window_title(rom_info));
+                    window.palette = overrides.palette(rom_info).unwrap_or_default();
+                    match (gamepad_mapping(&path, options.pad_path.as_deref(), rom_info), window.gamepad.as_mut()) {
+                        (Ok(mapping), Some(gamepad)) => gamepad.set_mapping(mapping),
+                        (Err(e), _) => eprintln!("Error: Bad gamepad mapping: {}", e),
+                        _ => (),
+                    }
+                }
+                Err(e) => eprintln!("Error: {}", e),
             }
+            // the time spent typing shouldn't be caught up on
+            clock = SystemClock::new();
         }
 
-        let beep: bool = chip.decrement_timers();
-        if beep {
-            sink.play();
-        } else {
-            sink.pause();
+        if let Some(message) = watch.as_mut().and_then(|watch| watch.poll(emulator)) {
+            println!("{}", message);
+            frontend::present(emulator, &mut window);
+        }
+
+        let output = frontend::tick(emulator, &mut clock, &mut window, &mut audio);
+        print_warnings(&mut emulator.vm);
+        if let Some(addr) = output.breakpoint {
+            // check if current pc is in breakpoints to pause at
+            println!("Hit a breakpoint at {:#x}", addr);
+            debug = true;
+        }
+        if let Some(e) = output.error {
+            eprintln!("Error: {}", e);
+            debug = true;
         }
+    }
+}
+
+#[cfg(feature = "tui")]
+struct TuiOptions {
+    enabled: bool,
+    glyphs: Glyphs,
+    beep: BeepStyle,
+    panel: bool,
+}
 
-        if redraw {
-            for (i, px) in chip.get_display().into_iter().enumerate() {
-                // px is u8, either 0x1 or 0x0
-                let row = i / DISPLAY_WIDTH;
-                let col = i % DISPLAY_WIDTH;
+#[cfg(feature = "tui")]
+fn run_terminal(emulator: &mut Emulator, options: &TuiOptions, overrides: &Overrides, mut debug: bool, watch: &mut Option<Watch>) {
+    let mut screen = TerminalFrontend::new(options.glyphs).unwrap_or_else(|e| {
+        eprintln!("Error: Could not set up the terminal: {}", e);
+        process::exit(1);
+    });
+    screen.panel = options.panel;
+    screen.palette = overrides.palette(emulator.rom_info());
+    screen.status = String::from("Esc quits, P pauses, N advances a frame, Tab toggles fast-forward, O loads a ROM");
+    let mut beeper = screen.beeper(options.beep);
+    let mut clock = SystemClock::new();
+    // stderr would draw over the screen, so warnings and errors wait until the terminal is handed back
+    let mut messages = Vec::new();
+
+    frontend::present(emulator, &mut screen);
+
+    while !screen.should_quit() {
+        if debug {
+            // the debugger reads whole lines, which raw mode would get in the way of
+            let _ = screen.suspend();
+            for message in messages.drain(..) {
+                eprintln!("{}", message);
+            }
+            while debug {
+                match run_debugger(&emulator.vm, &mut emulator.breakpoints) {
+                    DebugAction::Run => {
+                        debug = false;
+                        emulator.scheduler.set_paused(false);
+                    }
+                    DebugAction::Step => {
+                        if let Some(e) = emulator.step(&screen.poll()).error {
+                            eprintln!("Error: {}", e);
+                        }
+                        print_warnings(&mut emulator.vm);
+                    }
+                }
+            }
+            let _ = screen.resume();
+            frontend::present(emulator, &mut screen);
+        }
 
-                for row_offset in 0..PX_SCALING  {
-                    let buf_idx = row*win_width*PX_SCALING + col*PX_SCALING + row_offset*win_width;
-                    buffer[buf_idx..buf_idx+PX_SCALING].fill(if *px == 0 {0} else {ON_PIXEL});
+        for c in screen.take_hotkeys() {
+            let hotkey = match c {
+                'p' => Hotkey::TogglePause,
+                'n' => Hotkey::AdvanceFrame,
+                '-' => Hotkey::SlowDown,
+                '=' => Hotkey::SpeedUp,
+                '[' => Hotkey::FewerCycles,
+                ']' => Hotkey::MoreCycles,
+                'g' => Hotkey::NextFilter,
+                '\x08' => Hotkey::Reset,
+                'k' => {
+                    if let Some(message) = watch.as_ref().and_then(|watch| watch.save_state(emulator)) {
+                        screen.status = message;
+                    }
+                    continue;
                 }
+                'o' => {
+                    let _ = screen.suspend();
+                    screen.status = match open_rom(emulator, overrides, watch) {
+                        Ok((message, _)) => {
+                            screen.palette = overrides.palette(emulator.rom_info());
+                            message
+                        }
+                        Err(e) => e,
+                    };
+                    let _ = screen.resume();
+                    clock = SystemClock::new();
+                    frontend::present(emulator, &mut screen);
+                    continue;
+                }
+                // releases usually can't be detected, so fast-forward toggles instead
+                '\t' => {
+                    let scheduler = &mut emulator.scheduler;
+                    scheduler.set_fast_forward(!scheduler.is_fast_forward());
+                    screen.status = String::from(if scheduler.is_fast_forward() { "Fast-forward" } else { "Normal speed" });
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(message) = apply_hotkey(emulator, hotkey) {
+                screen.status = message;
             }
+        }
 
-            window
-                .update_with_buffer(&buffer, win_width, win_height)
-                .unwrap();
-            
-            redraw = false;
+        if let Some(message) = watch.as_mut().and_then(|watch| watch.poll(emulator)) {
+            screen.status = message;
+            frontend::present(emulator, &mut screen);
+        }
+
+        let output = frontend::tick(emulator, &mut clock, &mut screen, &mut beeper);
+        let diagnostics = emulator.vm.take_diagnostics();
+        if let Some(diagnostic) = diagnostics.first() {
+            screen.status = match diagnostics.len() {
+                1 => format!("Warning: {}", diagnostic),
+                n => format!("Warning: {} (and {} more)", diagnostic, n - 1),
+            };
+            messages.extend(diagnostics.iter().map(|diagnostic| format!("Warning: {}", diagnostic)));
+        }
+        // the whole list is in the --selfmod summary on exit
+        let patches = emulator.vm.take_patches();
+        if let Some(patch) = patches.first() {
+            screen.status = match patches.len() {
+                1 => format!("Self-modifying code: {}", patch),
+                n => format!("Self-modifying code: {} (and {} more)", patch, n - 1),
+            };
+        }
+        if output.frames > 0 || output.breakpoint.is_some() || output.error.is_some() {
+            screen.draw_panel(&emulator.vm);
+        }
+        if let Some(addr) = output.breakpoint {
+            screen.status = format!("Hit a breakpoint at {:#x}", addr);
+            debug = true;
+        }
+        if let Some(e) = output.error {
+            // shown again once the debugger gives the terminal back
+            screen.status = format!("Error: {}", e);
+            messages.push(screen.status.clone());
+            debug = true;
         }
     }
-}
 
+    drop(screen);
+    for message in messages {
+        eprintln!("{}", message);
+    }
+}
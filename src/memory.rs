@@ -1,9 +1,17 @@
-const MEM_SIZE: usize = 4096; 
+pub const MEM_SIZE: usize = 4096; 
+pub const LAST_INSTR_ADDR: u16 = (MEM_SIZE - 2) as u16; // last address a whole instruction fits at
+pub const ADDR_MASK: u16 = (MEM_SIZE - 1) as u16; // addresses wrap around at the end of memory, 12 bits. XO-CHIP's 64kb isn't supported
 
 pub struct Memory {
     memory: [u8; MEM_SIZE], // 4kb RAM
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Memory {
     pub fn new() -> Memory {
         Memory {
@@ -15,26 +23,55 @@ impl Memory {
         self.memory.as_ptr()
     }
 
+    // for whoever writes to memory from outside, e.g. cheats in a libretro frontend
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.memory.as_mut_ptr()
+    }
+
+    // the second byte of an instruction at 0xfff is the one at 0
     pub fn get_instr(&self, pc: u16) -> u16 {
-        (self.memory[pc as usize] as u16) << 8 | self.memory[pc as usize+1] as u16
+        (self.get(pc) as u16) << 8 | self.get(pc.wrapping_add(1)) as u16
     }
 
+    // copies up to `size` bytes of `target` to `start`, leaving out whatever is past the end
+    // of memory
     pub fn map_range(&mut self, start: usize, size: usize, target: &[u8]) {
-        self.memory[start..start+size].copy_from_slice(target);
+        let range = Self::clamp(start, size.min(target.len()));
+        let len = range.len();
+        self.memory[range].copy_from_slice(&target[..len]);
     }
 
+    // cut short at the end of memory
     pub fn get_range(&self, start: usize, size: usize) -> &[u8] {
-        &self.memory[start..start+size]
+        &self.memory[Self::clamp(start, size)]
+    }
+
+    fn clamp(start: usize, size: usize) -> std::ops::Range<usize> {
+        let start = start.min(MEM_SIZE);
+        start..start.saturating_add(size).min(MEM_SIZE)
     }
 
-    // set single byte at specific address
+    // set single byte at specific address, wrapping around like the VM's
     pub fn set(&mut self, index: u16, data: u8) {
-        self.memory[index as usize] = data;
+        self.memory[(index & ADDR_MASK) as usize] = data;
     }
 
-    // get single byte at specific address
+    // get single byte at specific address, wrapping around like the VM's
     pub fn get(&self, index: u16) -> u8 {
-        self.memory[index as usize]
+        self.memory[(index & ADDR_MASK) as usize]
+    }
+
+    // fills `buf` starting at `start`, carrying on from address 0 past the end of memory
+    pub fn read_wrapping(&self, start: u16, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.memory[(start.wrapping_add(i as u16) & ADDR_MASK) as usize];
+        }
+    }
+
+    pub fn write_wrapping(&mut self, start: u16, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.memory[(start.wrapping_add(i as u16) & ADDR_MASK) as usize] = *byte;
+        }
     }
 }
 
@@ -0,0 +1,119 @@
+use std::fmt;
+
+// colors are 0x00RRGGBB, indexed by pixel value: background, first plane, second plane and
+// both planes overlapping. with a single plane only the first two are ever used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub colors: [u32; 4],
+}
+
+pub const CLASSIC: Palette = Palette { colors: [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555] };
+pub const AMBER: Palette = Palette { colors: [0x1A0F00, 0xFFB000, 0xA36F00, 0x5C3E00] };
+pub const LCD_GREEN: Palette = Palette { colors: [0x9BBC0F, 0x0F380F, 0x8BAC0F, 0x306230] };
+pub const OCTO: Palette = Palette { colors: [0x996600, 0xFFCC00, 0xFF6600, 0x662200] };
+
+pub const THEMES: [(&str, Palette); 4] = [
+    ("classic", CLASSIC),
+    ("amber", AMBER),
+    ("lcd", LCD_GREEN),
+    ("octo", OCTO),
+];
+
+impl Default for Palette {
+    fn default() -> Self {
+        CLASSIC
+    }
+}
+
+impl Palette {
+    // background and foreground, with the second plane colors blended from them
+    pub const fn two(background: u32, foreground: u32) -> Palette {
+        Palette { colors: [background, foreground, blend(background, foreground, 2), blend(background, foreground, 1)] }
+    }
+
+    pub fn from_name(name: &str) -> Option<Palette> {
+        THEMES.iter().find(|(n, _)| *n == name).map(|(_, palette)| *palette)
+    }
+
+    // a theme name, or 2 or 4 comma separated hex colors such as `#000000,#33ff66`
+    pub fn parse(s: &str) -> Option<Palette> {
+        if let Some(palette) = Palette::from_name(s) {
+            return Some(palette);
+        }
+
+        let colors: Option<Vec<u32>> = s
+            .split(',')
+            .map(|c| {
+                let c = c.trim().trim_start_matches('#');
+                if c.len() == 6 { u32::from_str_radix(c, 16).ok() } else { None }
+            })
+            .collect();
+
+        match colors?.as_slice() {
+            [bg, fg] => Some(Palette::two(*bg, *fg)),
+            [bg, fg, plane2, both] => Some(Palette { colors: [*bg, *fg, *plane2, *both] }),
+            _ => None,
+        }
+    }
+
+    pub fn background(&self) -> u32 {
+        self.colors[0]
+    }
+
+    pub fn color(&self, pixel: u8) -> u32 {
+        self.colors[(pixel & 0x3) as usize]
+    }
+
+    // between the background and the first plane's color, for filtered output
+    pub fn shade(&self, level: u8) -> u32 {
+        match level {
+            0 => self.colors[0],
+            255 => self.colors[1],
+            _ => mix(self.colors[0], self.colors[1], level as u32),
+        }
+    }
+
+    // brightness levels as presented by the emulator, to 0x00RRGGBB per pixel as minifb
+    // and libretro expect
+    pub fn render_rgb(&self, levels: &[u8], out: &mut [u32]) {
+        for (px, level) in out.iter_mut().zip(levels) {
+            *px = self.shade(*level);
+        }
+    }
+
+    // 4 bytes per pixel, as canvas ImageData expects
+    pub fn render_rgba(&self, levels: &[u8], out: &mut [u8]) {
+        for (px, level) in out.chunks_exact_mut(4).zip(levels) {
+            let [_, r, g, b] = self.shade(*level).to_be_bytes();
+            px.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+}
+
+impl fmt::Display for Palette {
+    // css hex colors, the same format `parse` accepts
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let colors: Vec<String> = self.colors.iter().map(|c| format!("#{:06x}", c)).collect();
+        f.write_str(&colors.join(","))
+    }
+}
+
+// `weight` 255ths of the way from `a` to `b`
+fn mix(a: u32, b: u32, weight: u32) -> u32 {
+    (0..24).step_by(8).fold(0, |color, shift| {
+        let (ca, cb) = ((a >> shift) & 0xFF, (b >> shift) & 0xFF);
+        color | ((ca * (255 - weight) + cb * weight) / 255) << shift
+    })
+}
+
+// `weight` thirds of the way from `a` to `b`
+const fn blend(a: u32, b: u32, weight: u32) -> u32 {
+    let mut color = 0;
+    let mut shift = 0;
+    while shift < 24 {
+        let (ca, cb) = ((a >> shift) & 0xFF, (b >> shift) & 0xFF);
+        color |= ((ca * (3 - weight) + cb * weight) / 3) << shift;
+        shift += 8;
+    }
+    color
+}
@@ -0,0 +1,189 @@
+// software CRT effects applied to the scaled framebuffer (0x00RRGGBB pixels), so the
+// retro look works without a GPU in both the desktop window and the browser
+
+pub const PRESETS: [&str; 4] = ["off", "scanlines", "crt", "glow"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    Scanlines { strength: f32 },             // darkens every other row by this fraction
+    Bloom { strength: f32, radius: usize },  // adds a blurred copy on top, so lit pixels glow
+    Curvature { amount: f32 },               // bulges the picture like a curved tube
+    Vignette { strength: f32 },              // darkens towards the corners
+}
+
+// effects run in order, each on the output of the one before
+#[derive(Clone, Debug, Default)]
+pub struct PostFx {
+    pub effects: Vec<Effect>,
+    copy: Vec<u32>,            // source for effects that move pixels around
+    sums: Vec<[u32; 3]>,       // per channel box blur sums for bloom
+    column: Vec<[u32; 3]>,
+}
+
+impl PostFx {
+    pub fn new(effects: Vec<Effect>) -> PostFx {
+        PostFx { effects, ..PostFx::default() }
+    }
+
+    // one of `PRESETS`
+    pub fn preset(name: &str) -> Option<PostFx> {
+        let effects = match name {
+            "off" => vec![],
+            "scanlines" => vec![Effect::Scanlines { strength: 0.4 }],
+            "crt" => vec![
+                Effect::Bloom { strength: 0.35, radius: 4 },
+                Effect::Scanlines { strength: 0.3 },
+                Effect::Curvature { amount: 0.06 },
+                Effect::Vignette { strength: 0.4 },
+            ],
+            "glow" => vec![
+                Effect::Bloom { strength: 0.6, radius: 6 },
+                Effect::Vignette { strength: 0.25 },
+            ],
+            _ => return None,
+        };
+        Some(PostFx::new(effects))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn apply(&mut self, buffer: &mut [u32], size: (usize, usize)) {
+        if size.0 == 0 || size.1 == 0 {
+            return;
+        }
+
+        for i in 0..self.effects.len() {
+            match self.effects[i] {
+                Effect::Scanlines { strength } => scanlines(buffer, size, strength),
+                Effect::Bloom { strength, radius } => self.bloom(buffer, size, strength, radius),
+                Effect::Curvature { amount } => self.curvature(buffer, size, amount),
+                Effect::Vignette { strength } => vignette(buffer, size, strength),
+            }
+        }
+    }
+
+    fn bloom(&mut self, buffer: &mut [u32], (width, height): (usize, usize), strength: f32, radius: usize) {
+        // separable box blur with running sums, pixels past the edges count as black
+        self.sums.resize(width * height, [0; 3]);
+        for (row, sums) in buffer.chunks_exact(width).zip(self.sums.chunks_exact_mut(width)) {
+            let mut acc = [0; 3];
+            for px in row.iter().take(radius) {
+                add(&mut acc, channels(*px));
+            }
+            for x in 0..width {
+                if x + radius < width {
+                    add(&mut acc, channels(row[x + radius]));
+                }
+                if x > radius {
+                    sub(&mut acc, channels(row[x - radius - 1]));
+                }
+                sums[x] = acc;
+            }
+        }
+
+        self.column.resize(height, [0; 3]);
+        for x in 0..width {
+            for y in 0..height {
+                self.column[y] = self.sums[y*width + x];
+            }
+
+            let mut acc = [0; 3];
+            for sum in self.column.iter().take(radius) {
+                add(&mut acc, *sum);
+            }
+            for y in 0..height {
+                if y + radius < height {
+                    add(&mut acc, self.column[y + radius]);
+                }
+                if y > radius {
+                    sub(&mut acc, self.column[y - radius - 1]);
+                }
+                self.sums[y*width + x] = acc;
+            }
+        }
+
+        let area = ((2*radius + 1) * (2*radius + 1)) as f32;
+        for (px, sum) in buffer.iter_mut().zip(self.sums.iter()) {
+            let [r, g, b] = channels(*px);
+            let glow = |c: u32, s: u32| c as f32 + s as f32 / area * strength;
+            *px = pack(glow(r, sum[0]), glow(g, sum[1]), glow(b, sum[2]));
+        }
+    }
+
+    fn curvature(&mut self, buffer: &mut [u32], (width, height): (usize, usize), amount: f32) {
+        self.copy.clear();
+        self.copy.extend_from_slice(buffer);
+
+        for (y, row) in buffer.chunks_exact_mut(width).enumerate() {
+            let v = centered(y, height);
+            for (x, px) in row.iter_mut().enumerate() {
+                let u = centered(x, width);
+                // sampling further out the closer to the edges, the picture bends away
+                let su = u * (1.0 + amount * v * v);
+                let sv = v * (1.0 + amount * u * u);
+                *px = if su.abs() > 1.0 || sv.abs() > 1.0 {
+                    0
+                } else {
+                    let sx = (((su + 1.0) * 0.5 * width as f32) as usize).min(width - 1);
+                    let sy = (((sv + 1.0) * 0.5 * height as f32) as usize).min(height - 1);
+                    self.copy[sy*width + sx]
+                };
+            }
+        }
+    }
+}
+
+fn scanlines(buffer: &mut [u32], (width, _): (usize, usize), strength: f32) {
+    for row in buffer.chunks_exact_mut(width).skip(1).step_by(2) {
+        for px in row.iter_mut() {
+            *px = scale(*px, 1.0 - strength);
+        }
+    }
+}
+
+fn vignette(buffer: &mut [u32], (width, height): (usize, usize), strength: f32) {
+    for (y, row) in buffer.chunks_exact_mut(width).enumerate() {
+        let v = centered(y, height);
+        for (x, px) in row.iter_mut().enumerate() {
+            let u = centered(x, width);
+            *px = scale(*px, 1.0 - strength * (u*u + v*v) / 2.0);
+        }
+    }
+}
+
+// 0x00RRGGBB pixels to 4 bytes each, as canvas ImageData expects
+pub fn to_rgba(pixels: &[u32], out: &mut [u8]) {
+    for (px, color) in out.chunks_exact_mut(4).zip(pixels) {
+        let [_, r, g, b] = color.to_be_bytes();
+        px.copy_from_slice(&[r, g, b, 0xFF]);
+    }
+}
+
+// position from -1 to 1 across `size`
+fn centered(i: usize, size: usize) -> f32 {
+    (i as f32 + 0.5) / size as f32 * 2.0 - 1.0
+}
+
+fn channels(color: u32) -> [u32; 3] {
+    [(color >> 16) & 0xFF, (color >> 8) & 0xFF, color & 0xFF]
+}
+
+fn pack(r: f32, g: f32, b: f32) -> u32 {
+    let c = |v: f32| v.clamp(0.0, 255.0) as u32;
+    (c(r) << 16) | (c(g) << 8) | c(b)
+}
+
+fn scale(color: u32, factor: f32) -> u32 {
+    let [r, g, b] = channels(color);
+    pack(r as f32 * factor, g as f32 * factor, b as f32 * factor)
+}
+
+fn add(acc: &mut [u32; 3], c: [u32; 3]) {
+    acc.iter_mut().zip(c).for_each(|(a, c)| *a += c);
+}
+
+fn sub(acc: &mut [u32; 3], c: [u32; 3]) {
+    acc.iter_mut().zip(c).for_each(|(a, c)| *a -= c);
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::disasm;
+use crate::memory::{Memory, MEM_SIZE};
+use crate::vm::{VM, START_ADDR};
+
+const HOT_SPOTS: usize = 20;
+
+// instructions run and the machine cycles they took
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Time {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Time {
+    fn add(&mut self, other: Time) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+// a subroutine as reached through one particular chain of calls
+struct Node {
+    entry: u16,
+    parent: usize,
+    depth: usize,
+    children: HashMap<u16, usize>,
+    time: Time, // spent in the subroutine itself, not in what it calls
+}
+
+impl Node {
+    fn new(entry: u16, parent: usize, depth: usize) -> Node {
+        Node { entry, parent, depth, children: HashMap::new(), time: Time::default() }
+    }
+}
+
+// where a program spends its time: instructions and COSMAC VIP machine cycles per address,
+// and per subroutine following 2NNN and 00EE. cycles are counted whatever the timing mode
+pub struct Profiler {
+    pub instructions: Vec<u64>, // per address
+    pub cycles: Vec<u64>,
+    pub calls: HashMap<u16, u64>, // per subroutine
+    pub frames: u64,
+    pub max_calls_per_frame: u64,
+    pub draw_instructions: u64,   // DXYN
+    pub draw_cycles: u64,
+    pub vblank_cycles: u64,       // lost waiting for the vertical blank after DXYN, VIP timing only
+    pub key_wait_instructions: u64, // FX0A run again because no key was pressed
+    nodes: Vec<Node>, // the first is the program's entry point, parents come before children
+    current: usize,
+    frame_calls: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            instructions: vec![0; MEM_SIZE],
+            cycles: vec![0; MEM_SIZE],
+            calls: HashMap::new(),
+            frames: 0,
+            max_calls_per_frame: 0,
+            draw_instructions: 0,
+            draw_cycles: 0,
+            vblank_cycles: 0,
+            key_wait_instructions: 0,
+            nodes: vec![Node::new(START_ADDR as u16, 0, 0)],
+            current: 0,
+            frame_calls: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Profiler::new();
+    }
+
+    // `instr` at `pc` just ran and took `cycles`, leaving the machine as `vm`
+    pub fn record(&mut self, pc: u16, instr: u16, cycles: u32, vm: &VM) {
+        self.instructions[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles as u64;
+        self.nodes[self.current].time.add(Time { instructions: 1, cycles: cycles as u64 });
+
+        match instr & 0xF000 {
+            0x0000 if instr == 0x00EE => self.current = self.nodes[self.current].parent,
+            0x2000 => self.enter(instr & 0x0FFF),
+            0xD000 => {
+                self.draw_instructions += 1;
+                self.draw_cycles += cycles as u64;
+            }
+            0xF000 if instr & 0x00FF == 0x0A && vm.pc == pc => self.key_wait_instructions += 1,
+            _ => (),
+        }
+
+        // the stack was changed some other way, e.g. by a reset
+        while self.nodes[self.current].depth > vm.stack.sp() {
+            self.current = self.nodes[self.current].parent;
+        }
+    }
+
+    fn enter(&mut self, entry: u16) {
+        *self.calls.entry(entry).or_insert(0) += 1;
+        self.frame_calls += 1;
+        let parent = self.current;
+        let depth = self.nodes[parent].depth + 1;
+        self.current = match self.nodes[parent].children.get(&entry) {
+            Some(&child) => child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node::new(entry, parent, depth));
+                self.nodes[parent].children.insert(entry, child);
+                child
+            }
+        };
+    }
+
+    // `vblank_cycles` were spent waiting for the frame to end rather than running anything
+    pub fn end_frame(&mut self, vblank_cycles: u32) {
+        self.frames += 1;
+        self.max_calls_per_frame = self.max_calls_per_frame.max(self.frame_calls);
+        self.frame_calls = 0;
+        self.vblank_cycles += vblank_cycles as u64;
+    }
+
+    pub fn total(&self) -> Time {
+        let mut total = Time::default();
+        for node in &self.nodes {
+            total.add(node.time);
+        }
+        total
+    }
+
+    fn per_frame(&self, count: u64) -> f64 {
+        count as f64 / self.frames.max(1) as f64
+    }
+
+    // (self, total) instructions and cycles per subroutine. recursive calls are only
+    // counted once towards the total
+    fn subroutines(&self) -> HashMap<u16, (Time, Time)> {
+        let mut subtree: Vec<Time> = self.nodes.iter().map(|node| node.time).collect();
+        for (n, node) in self.nodes.iter().enumerate().skip(1).rev() {
+            let time = subtree[n];
+            subtree[node.parent].add(time);
+        }
+
+        let mut subroutines: HashMap<u16, (Time, Time)> = HashMap::new();
+        for (n, node) in self.nodes.iter().enumerate() {
+            let (own, total) = subroutines.entry(node.entry).or_default();
+            own.add(node.time);
+            if !self.ancestors(n).any(|ancestor| self.nodes[ancestor].entry == node.entry) {
+                total.add(subtree[n]);
+            }
+        }
+        subroutines
+    }
+
+    fn ancestors(&self, mut n: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::from_fn(move || {
+            if n == 0 {
+                return None;
+            }
+            n = self.nodes[n].parent;
+            Some(n)
+        })
+    }
+
+    // the busiest addresses, the time spent in each subroutine and in drawing and
+    // waiting for keys. `memory` is for disassembling the hot spots
+    pub fn report(&self, memory: &Memory) -> String {
+        let Time { instructions, cycles } = self.total();
+        let calls: u64 = self.calls.values().sum();
+        let mut report = String::new();
+        let _ = writeln!(report, "; {} frames, {} instructions ({:.1} per frame), {} cycles ({:.1} per frame)",
+            self.frames, instructions, self.per_frame(instructions), cycles, self.per_frame(cycles));
+        let _ = writeln!(report, "; {} calls, {:.1} per frame and at most {} in one frame", calls, self.per_frame(calls), self.max_calls_per_frame);
+
+        report.push_str("\n; hot spots, by cycles\n");
+        let _ = writeln!(report, "{:>12} {:>12} {:>6}  addr  opcode", "instructions", "cycles", "%");
+        let mut addrs: Vec<usize> = (0..MEM_SIZE).filter(|addr| self.instructions[*addr] > 0).collect();
+        addrs.sort_by_key(|addr| (std::cmp::Reverse(self.cycles[*addr]), std::cmp::Reverse(self.instructions[*addr]), *addr));
+        for &addr in addrs.iter().take(HOT_SPOTS) {
+            let instr = memory.get_instr(addr as u16);
+            let _ = writeln!(report, "{:>12} {:>12} {:>5.1}%  {:03x}   {:04x}  {}",
+                self.instructions[addr], self.cycles[addr], percent(self.cycles[addr], cycles), addr, instr, disasm::disassemble(instr));
+        }
+
+        report.push_str("\n; subroutines, by total cycles including what they call\n");
+        let _ = writeln!(report, "{:<9} {:>8} {:>9} {:>12} {:>12} {:>12} {:>12}", "", "calls", "per frame", "instructions", "total", "cycles", "total");
+        let mut subroutines: Vec<_> = self.subroutines().into_iter().collect();
+        subroutines.sort_by_key(|(entry, (_, total))| (std::cmp::Reverse(total.cycles), *entry));
+        for (entry, (own, total)) in subroutines {
+            let calls = self.calls.get(&entry).copied().unwrap_or(0);
+            let _ = writeln!(report, "{:<9} {:>8} {:>9.1} {:>12} {:>12} {:>12} {:>12}",
+                name(entry), calls, self.per_frame(calls), own.instructions, total.instructions, own.cycles, total.cycles);
+        }
+
+        report.push('\n');
+        let _ = writeln!(report, "; DXYN: {} instructions ({:.1}%), {} cycles ({:.1}%), and {} cycles waiting for the vertical blank",
+            self.draw_instructions, percent(self.draw_instructions, instructions), self.draw_cycles, percent(self.draw_cycles, cycles), self.vblank_cycles);
+        let _ = writeln!(report, "; FX0A: {} instructions ({:.1}%) waiting for a key",
+            self.key_wait_instructions, percent(self.key_wait_instructions, instructions));
+        report
+    }
+
+    // one line per call chain, e.g. "main;sub_2d4 1234", weighted by cycles or by
+    // instructions, which flamegraph tools turn into a flame graph
+    pub fn collapsed(&self, cycles: bool) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for (n, node) in self.nodes.iter().enumerate() {
+            let weight = if cycles { node.time.cycles } else { node.time.instructions };
+            if weight == 0 {
+                continue;
+            }
+            let mut path: Vec<String> = self.ancestors(n).map(|ancestor| name(self.nodes[ancestor].entry)).collect();
+            path.reverse();
+            path.push(name(node.entry));
+            lines.push(format!("{} {}", path.join(";"), weight));
+        }
+        lines.sort();
+        let mut collapsed = lines.join("\n");
+        collapsed.push('\n');
+        collapsed
+    }
+}
+
+// labels as the decompiler uses them
+fn name(entry: u16) -> String {
+    if entry == START_ADDR as u16 { String::from("main") } else { format!("sub_{:03x}", entry) }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * part as f64 / total as f64 }
+}
@@ -0,0 +1,82 @@
+// behaviours that differ between CHIP-8 interpreters
+// the default matches what this emulator has always done
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,           // 8XY6/8XYE shift VY into VX instead of shifting VX
+    pub load_store_increments_i: bool, // FX55/FX65 leave I pointing past the last register
+    pub jump_uses_vx: bool,            // BXNN jumps to XNN + VX instead of NNN + V0
+    pub vf_reset: bool,                // 8XY1/8XY2/8XY3 clear VF
+    pub clip_sprites: bool,            // sprites are cut off at the screen edge instead of wrapping
+    pub display_wait: bool,            // DXYN waits for the vertical blank, so ends the frame
+    pub index_overflow: bool,          // FX1E sets VF when I goes past 0xFFF, like the Amiga interpreter
+}
+
+impl Quirks {
+    // named after the fields, for frontends that configure quirks one at a time
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "shift_uses_vy" => Some(self.shift_uses_vy),
+            "load_store_increments_i" => Some(self.load_store_increments_i),
+            "jump_uses_vx" => Some(self.jump_uses_vx),
+            "vf_reset" => Some(self.vf_reset),
+            "clip_sprites" => Some(self.clip_sprites),
+            "display_wait" => Some(self.display_wait),
+            "index_overflow" => Some(self.index_overflow),
+            _ => None,
+        }
+    }
+
+    // returns false for an unknown name
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let quirk = match name {
+            "shift_uses_vy" => &mut self.shift_uses_vy,
+            "load_store_increments_i" => &mut self.load_store_increments_i,
+            "jump_uses_vx" => &mut self.jump_uses_vx,
+            "vf_reset" => &mut self.vf_reset,
+            "clip_sprites" => &mut self.clip_sprites,
+            "display_wait" => &mut self.display_wait,
+            "index_overflow" => &mut self.index_overflow,
+            _ => return false,
+        };
+        *quirk = enabled;
+        true
+    }
+
+    // original RCA COSMAC VIP interpreter
+    pub const fn vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+            index_overflow: false,
+        }
+    }
+
+    // CHIP-48 on the HP48, which most early 90s games were written against
+    pub const fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            index_overflow: false,
+        }
+    }
+
+    pub const fn modern() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            index_overflow: false,
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::palette::{Palette, LCD_GREEN};
+use crate::quirks::Quirks;
+use crate::stack::{MAX_STACK_DEPTH, VIP_STACK_DEPTH};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    CosmacVip, // written for the original RCA interpreter
+    Chip48,    // written for CHIP-48 on the HP48
+    Modern,    // written against modern emulators
+}
+
+impl Platform {
+    pub fn parse(s: &str) -> Option<Platform> {
+        match s {
+            "vip" => Some(Platform::CosmacVip),
+            "chip48" => Some(Platform::Chip48),
+            "modern" => Some(Platform::Modern),
+            _ => None,
+        }
+    }
+
+    pub const fn quirks(self) -> Quirks {
+        match self {
+            Platform::CosmacVip => Quirks::vip(),
+            Platform::Chip48 => Quirks::chip48(),
+            Platform::Modern => Quirks::modern(),
+        }
+    }
+
+    // instructions per frame that roughly match the platform's interpreter, which is what
+    // its games were timed against
+    pub const fn cycles_per_frame(self) -> u32 {
+        match self {
+            Platform::CosmacVip => 15,
+            Platform::Chip48 => 30,
+            Platform::Modern => 12,
+        }
+    }
+
+    // what the games were played on: a TV for the VIP and PC emulators, the HP48's LCD for CHIP-48
+    pub const fn palette(self) -> Option<Palette> {
+        match self {
+            Platform::Chip48 => Some(LCD_GREEN),
+            Platform::CosmacVip | Platform::Modern => None,
+        }
+    }
+
+    // call stack slots the platform's interpreter has
+    pub const fn stack_depth(self) -> usize {
+        match self {
+            Platform::CosmacVip => VIP_STACK_DEPTH,
+            Platform::Chip48 | Platform::Modern => MAX_STACK_DEPTH,
+        }
+    }
+}
+
+// which CHIP-8 key each logical control is on, used to set up gamepads
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyBindings {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+}
+
+impl KeyBindings {
+    const fn none() -> KeyBindings {
+        KeyBindings { up: None, down: None, left: None, right: None, a: None, b: None }
+    }
+
+    const fn dirs(up: u8, down: u8, left: u8, right: u8) -> KeyBindings {
+        KeyBindings { up: Some(up), down: Some(down), left: Some(left), right: Some(right), a: None, b: None }
+    }
+
+    const fn a(mut self, key: u8) -> KeyBindings {
+        self.a = Some(key);
+        self
+    }
+
+    const fn b(mut self, key: u8) -> KeyBindings {
+        self.b = Some(key);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct RomInfo {
+    pub sha1: &'static str,
+    pub title: &'static str,
+    pub author: &'static str,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub cycles_per_frame: Option<u32>,
+    pub keys: KeyBindings,
+    pub palette: Option<Palette>,
+}
+
+const fn rom(sha1: &'static str, title: &'static str, author: &'static str, platform: Platform) -> RomInfo {
+    RomInfo {
+        sha1,
+        title,
+        author,
+        platform,
+        quirks: platform.quirks(),
+        cycles_per_frame: Some(platform.cycles_per_frame()),
+        keys: KeyBindings::none(),
+        palette: platform.palette(),
+    }
+}
+
+impl RomInfo {
+    const fn keys(mut self, keys: KeyBindings) -> RomInfo {
+        self.keys = keys;
+        self
+    }
+
+    const fn quirks(mut self, quirks: Quirks) -> RomInfo {
+        self.quirks = quirks;
+        self
+    }
+}
+
+// the ROMs bundled in web/programs, keyed by SHA-1 like the chip-8-database project
+static ROMS: [RomInfo; 20] = [
+    rom("ea9af3c09b0d9e265fcd92bcc5d51a2939fdf27a", "15 Puzzle", "Roger Ivie", Platform::CosmacVip)
+        .keys(KeyBindings::dirs(0x2, 0x8, 0x4, 0x6)),
+    rom("a27dcf88a931f70c3ccf3c01a5410b263bac48bc", "Animal Race", "Brian Astle", Platform::CosmacVip),
+    rom("3368d56efeb584c509bafb548f1ee5e71ac1bc70", "Biorhythm", "Jef Winsor", Platform::CosmacVip),
+    rom("6f6509f38220e057a7e32ebb22dd353c1078e3e7", "Blitz", "David Winter", Platform::Modern)
+        .quirks(Quirks { clip_sprites: true, ..Quirks::modern() })
+        .keys(KeyBindings::none().a(0x5)),
+    rom("b3fed4ed1eb0ed693c9731dbe53b29a76236c781", "Bowling", "Gooitzen van der Wal", Platform::CosmacVip)
+        .keys(KeyBindings::none().a(0x5)),
+    rom("193915dcde1365ae054c4eaa21a35baa27cd3356", "Breakout", "Carmelo Cortez", Platform::CosmacVip)
+        .keys(KeyBindings { left: Some(0x4), right: Some(0x6), ..KeyBindings::none() }),
+    rom("91442577a6bbf8c3267f2df95fdfc50baebe176d", "Brick", "Andreas Gustafsson", Platform::Chip48)
+        .keys(KeyBindings { left: Some(0x4), right: Some(0x6), ..KeyBindings::none() }),
+    rom("2d10c07b532f4fa7c07a07324ba26ca39fe484fd", "Connect 4", "David Winter", Platform::Modern)
+        .keys(KeyBindings { left: Some(0x4), right: Some(0x6), ..KeyBindings::none() }.a(0x5)),
+    rom("35158696bd94ea22ef34e899fff1f15f7154d4fd", "Craps", "Carmelo Cortez", Platform::CosmacVip)
+        .keys(KeyBindings::none().a(0x5)),
+    rom("8e5f19d8ae9f3346779613359610967a5ed95fa8", "Deflection", "John Fort", Platform::CosmacVip),
+    rom("050f07a54371da79f924dd0227b89d07b4f2aed0", "Hidden", "David Winter", Platform::Modern)
+        .keys(KeyBindings::dirs(0x8, 0x2, 0x4, 0x6).a(0x5)),
+    rom("fc724ae0125f5f1ac94a79fe3afc6318b1f57556", "Kaleidoscope", "Joseph Weisbecker", Platform::CosmacVip)
+        .keys(KeyBindings::dirs(0x2, 0x8, 0x4, 0x6).a(0x0)),
+    rom("72e8f3a10a32bd7fb91322ecab87249f95e81e57", "Lunar Lander", "Udo Pernisz", Platform::CosmacVip),
+    rom("fa7c04f68d78e0faf6d136a3babe3943fc2e02f1", "Most Dangerous Game", "Peter Maruhnic", Platform::CosmacVip)
+        .keys(KeyBindings::dirs(0x2, 0x8, 0x4, 0x6).a(0x0)),
+    rom("b232ef880bd6060fb45fa6effed7edf0ae95670e", "Pong", "Paul Vervalin", Platform::Chip48)
+        .keys(KeyBindings { up: Some(0x1), down: Some(0x4), ..KeyBindings::none() }),
+    rom("4639f86beb0a203ae512b85d3b56d813b2dea7b4", "Rush Hour", "Hap", Platform::Modern)
+        .keys(KeyBindings::dirs(0x5, 0x8, 0x7, 0x9).a(0xA).b(0x1)),
+    rom("5c28a5f85289c9d859f95fd5eadbdcb1c30bb08b", "Space Invaders", "David Winter", Platform::Modern)
+        .keys(KeyBindings { left: Some(0x4), right: Some(0x6), ..KeyBindings::none() }.a(0x5)),
+    rom("89aadf7c28bcd1c11e71ad9bd6eeaf0e7be474f3", "Submarine", "Carmelo Cortez", Platform::CosmacVip)
+        .keys(KeyBindings::none().a(0x5)),
+    rom("5f518084744bf3cb8733f6e5454dfd1634320563", "Tetris", "Fran Dachille", Platform::Chip48)
+        .keys(KeyBindings { down: Some(0x1), left: Some(0x5), right: Some(0x6), ..KeyBindings::none() }.a(0x4)),
+    rom("bdb92475acfe11bc7814a2f5eade13fcd09b756a", "UFO", "Lutz V", Platform::Chip48)
+        .keys(KeyBindings { up: Some(0x5), left: Some(0x4), right: Some(0x6), ..KeyBindings::none() }),
+];
+
+pub fn hash(program: &[u8]) -> String {
+    sha1_smol::Sha1::from(program).digest().to_string()
+}
+
+pub fn lookup(program: &[u8]) -> Option<&'static RomInfo> {
+    let sha1 = hash(program);
+    ROMS.iter().find(|info| info.sha1 == sha1)
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cfg::Cfg;
+use crate::memory::{Memory, ADDR_MASK, MEM_SIZE};
+use crate::vm::{VM, FONT_END_ADDR, FONT_HEIGHT, FONT_START_ADDR, START_ADDR};
+
+// something a program did that real interpreters leave undefined, or that is almost
+// certainly a bug. addresses are where it happened in memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Problem {
+    UninitializedRead(u16),  // memory that was never written, neither by the program nor the ROM
+    BadFontDigit(u8),        // FX29 with VX past 0xF
+    IndexOutOfRange(u16),    // I, or memory accessed through it, past 0xFFF
+    SpriteFromReserved(u16), // DXYN reading below 0x200, other than a whole font character
+    CodeWrite(u16),          // FX33/FX55 writing over an instruction that ran or can be reached
+    StackUnderflow,          // 00EE with nothing on the stack
+    OddJump(u16),            // jump or call to an odd address
+    PcOutOfRange(u16),       // PC, or a BNNN target, past 0xFFE where no whole instruction fits
+}
+
+impl Problem {
+    // problems of the same kind at the same PC are only reported once
+    fn kind(&self) -> u8 {
+        match self {
+            Problem::UninitializedRead(_) => 0,
+            Problem::BadFontDigit(_) => 1,
+            Problem::IndexOutOfRange(_) => 2,
+            Problem::SpriteFromReserved(_) => 3,
+            Problem::CodeWrite(_) => 4,
+            Problem::StackUnderflow => 5,
+            Problem::OddJump(_) => 6,
+            Problem::PcOutOfRange(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UninitializedRead(addr) => write!(f, "read of {:#05x}, which was never written", addr),
+            Problem::BadFontDigit(digit) => write!(f, "font character {:#x} requested, only 0 to f exist", digit),
+            Problem::IndexOutOfRange(addr) => write!(f, "I reaches {:#x}, past the end of memory, and wraps around", addr),
+            Problem::SpriteFromReserved(addr) => write!(f, "sprite data read from {:#05x}, in the interpreter and font area", addr),
+            Problem::CodeWrite(addr) => write!(f, "write to {:#05x}, inside the program's code", addr),
+            Problem::StackUnderflow => write!(f, "return with nothing on the stack"),
+            Problem::OddJump(addr) => write!(f, "jump to odd address {:#05x}", addr),
+            Problem::PcOutOfRange(addr) => write!(f, "PC reaches {:#x}, past the last instruction that fits in memory", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub pc: u16,
+    pub opcode: u16,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#05x} ({:04x}): {}", self.pc, self.opcode, self.problem)
+    }
+}
+
+// strict mode: looks at every instruction before it runs and reports anything undefined
+// it is about to do. the instruction still runs as usual afterwards
+pub struct Sanitizer {
+    written: Vec<bool>, // per address, whether anything has been stored there
+    code: Vec<bool>,    // per address, whether it's part of an instruction that ran or is reachable from 0x200
+    seen: HashSet<(u16, u8)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Sanitizer {
+    // `memory` holds the program, `rom_len` bytes long
+    pub fn new(memory: &Memory, rom_len: usize) -> Sanitizer {
+        let mut sanitizer = Sanitizer {
+            written: vec![false; MEM_SIZE],
+            code: vec![false; MEM_SIZE],
+            seen: HashSet::new(),
+            diagnostics: Vec::new(),
+        };
+        sanitizer.reset(memory, rom_len);
+        sanitizer
+    }
+
+    // back to power on, with only the fonts and the program in memory
+    pub fn reset(&mut self, memory: &Memory, rom_len: usize) {
+        self.written.fill(false);
+        self.written[FONT_START_ADDR..FONT_END_ADDR].fill(true);
+        self.load_program(memory, rom_len);
+        self.seen.clear();
+    }
+
+    // a new version of the program was written over the old one. the ROM's data isn't
+    // code, so only what the analysis can reach counts until more of it runs
+    pub fn load_program(&mut self, memory: &Memory, rom_len: usize) {
+        self.written[START_ADDR..START_ADDR + rom_len].fill(true);
+        self.code.fill(false);
+        let cfg = Cfg::build(memory, &[START_ADDR as u16]);
+        for (addr, _) in cfg.blocks.values().flat_map(|block| block.instructions.iter()) {
+            self.mark_code(*addr as usize);
+        }
+    }
+
+    fn mark_code(&mut self, addr: usize) {
+        self.code[addr & ADDR_MASK as usize] = true;
+        self.code[(addr + 1) & ADDR_MASK as usize] = true;
+    }
+
+    // for memory changed from outside the program, e.g. by a debugger or a save state
+    pub fn mark_written(&mut self, start: usize, len: usize) {
+        let end = (start + len).min(MEM_SIZE);
+        self.written[start.min(end)..end].fill(true);
+    }
+
+    // diagnostics since the last call, oldest first
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    pub fn check(&mut self, vm: &VM) {
+        let pc = vm.pc;
+        if pc as usize + 1 >= MEM_SIZE {
+            // half an instruction, if that
+            let opcode = if (pc as usize) < MEM_SIZE { (vm.memory.get(pc) as u16) << 8 } else { 0 };
+            self.report(pc, opcode, vec![Problem::PcOutOfRange(pc)]);
+            return;
+        }
+        let opcode = vm.memory.get_instr(pc);
+        let mut problems = Vec::new();
+
+        // executing memory counts as reading it
+        self.read(pc as usize, 2, &mut problems);
+        self.mark_code(pc as usize);
+
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let vx = vm.registers[x];
+        let nnn = opcode & 0x0FFF;
+        let i = vm.ir as usize;
+
+        match opcode & 0xF000 {
+            0x0000 if opcode == 0x00EE && vm.stack.sp() == 0 => problems.push(Problem::StackUnderflow),
+            0x1000 | 0x2000 if nnn & 1 == 1 => problems.push(Problem::OddJump(nnn)),
+            0xB000 => {
+                let offset = if vm.quirks.jump_uses_vx { vx } else { vm.registers[0] };
+                let target = nnn + offset as u16;
+                if target as usize + 1 >= MEM_SIZE {
+                    problems.push(Problem::PcOutOfRange(target));
+                } else if target & 1 == 1 {
+                    problems.push(Problem::OddJump(target));
+                }
+            }
+            0xD000 => {
+                let rows = (opcode & 0x000F) as usize;
+                // font characters are the one thing meant to be drawn from down there
+                let glyph = (FONT_START_ADDR..FONT_END_ADDR).contains(&i)
+                    && (i - FONT_START_ADDR).is_multiple_of(FONT_HEIGHT as usize)
+                    && rows <= FONT_HEIGHT as usize;
+                if i < START_ADDR && rows > 0 && !glyph {
+                    problems.push(Problem::SpriteFromReserved(i as u16));
+                } else {
+                    self.read(i, rows, &mut problems);
+                }
+            }
+            0xF000 => match opcode & 0x00FF {
+                0x1E if i + vx as usize > ADDR_MASK as usize => problems.push(Problem::IndexOutOfRange((i + vx as usize) as u16)),
+                0x29 if vx > 0xF => problems.push(Problem::BadFontDigit(vx)),
+                0x33 => self.write(i, 3, &mut problems),
+                0x55 => self.write(i, x + 1, &mut problems),
+                0x65 => self.read(i, x + 1, &mut problems),
+                _ => (),
+            },
+            _ => (),
+        }
+
+        self.report(pc, opcode, problems);
+    }
+
+    fn report(&mut self, pc: u16, opcode: u16, problems: Vec<Problem>) {
+        for problem in problems {
+            if self.seen.insert((pc, problem.kind())) {
+                self.diagnostics.push(Diagnostic { pc, opcode, problem });
+            }
+        }
+    }
+
+    // the addresses actually accessed, which wrap around like the VM's
+    fn range(start: usize, len: usize, problems: &mut Vec<Problem>) -> impl Iterator<Item = usize> {
+        if start + len > MEM_SIZE {
+            problems.push(Problem::IndexOutOfRange((start + len - 1) as u16));
+        }
+        (start..start + len).map(|addr| addr & ADDR_MASK as usize)
+    }
+
+    fn read(&self, start: usize, len: usize, problems: &mut Vec<Problem>) {
+        if let Some(addr) = Self::range(start, len, problems).find(|addr| !self.written[*addr]) {
+            problems.push(Problem::UninitializedRead(addr as u16));
+        }
+    }
+
+    fn write(&mut self, start: usize, len: usize, problems: &mut Vec<Problem>) {
+        let mut overwritten = None;
+        for addr in Self::range(start, len, problems) {
+            if self.code[addr] && overwritten.is_none() {
+                overwritten = Some(addr);
+            }
+            self.written[addr] = true;
+        }
+        if let Some(addr) = overwritten {
+            problems.push(Problem::CodeWrite(addr as u16));
+        }
+    }
+}
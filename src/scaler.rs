@@ -0,0 +1,142 @@
+pub const DEFAULT_SCALE: usize = 10; // window pixels per CHIP-8 pixel at startup
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScaleMode {
+    Integer, // largest whole multiple that fits, letterboxed, so every pixel is the same size
+    Aspect,  // as large as fits while keeping the display's shape, letterboxed
+    Stretch, // fills the whole target
+}
+
+impl ScaleMode {
+    pub fn parse(s: &str) -> Option<ScaleMode> {
+        match s {
+            "integer" => Some(ScaleMode::Integer),
+            "aspect" => Some(ScaleMode::Aspect),
+            "stretch" => Some(ScaleMode::Stretch),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overlay {
+    None,
+    Grid,      // darkened lines between pixels
+    Scanlines, // darkened gaps between pixel rows
+}
+
+impl Overlay {
+    pub fn parse(s: &str) -> Option<Overlay> {
+        match s {
+            "none" => Some(Overlay::None),
+            "grid" => Some(Overlay::Grid),
+            "scanlines" => Some(Overlay::Scanlines),
+            _ => None,
+        }
+    }
+}
+
+// part of the target the display is drawn into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// scales 0x00RRGGBB pixels from the display's resolution up to a window or canvas. the
+// source size is passed on every call, so a change of resolution is picked up right away
+#[derive(Clone, Copy, Debug)]
+pub struct Scaler {
+    pub mode: ScaleMode,
+    pub overlay: Overlay,
+    pub border: u32, // color of the letterbox bars
+}
+
+impl Default for Scaler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scaler {
+    pub fn new() -> Scaler {
+        Scaler {
+            mode: ScaleMode::Integer,
+            overlay: Overlay::None,
+            border: 0x000000,
+        }
+    }
+
+    pub fn viewport(&self, src: (usize, usize), dst: (usize, usize)) -> Viewport {
+        let ((src_w, src_h), (dst_w, dst_h)) = (src, dst);
+        let (width, height) = match self.mode {
+            ScaleMode::Stretch => (dst_w, dst_h),
+            // a window smaller than the display still gets a picture, just not a sharp one
+            ScaleMode::Integer if dst_w >= src_w && dst_h >= src_h => {
+                let scale = (dst_w / src_w).min(dst_h / src_h);
+                (src_w * scale, src_h * scale)
+            }
+            ScaleMode::Integer | ScaleMode::Aspect => {
+                if dst_w * src_h > dst_h * src_w {
+                    (dst_h * src_w / src_h, dst_h)
+                } else {
+                    (dst_w, dst_w * src_h / src_w)
+                }
+            }
+        };
+
+        Viewport {
+            x: (dst_w - width) / 2,
+            y: (dst_h - height) / 2,
+            width,
+            height,
+        }
+    }
+
+    // `dst` is `dst_size.0 * dst_size.1` pixels, row by row
+    pub fn render(&self, src: &[u32], src_size: (usize, usize), dst: &mut [u32], dst_size: (usize, usize)) {
+        let ((src_w, src_h), (dst_w, _)) = (src_size, dst_size);
+        let view = self.viewport(src_size, dst_size);
+        if view.width == 0 || view.height == 0 {
+            dst.fill(self.border);
+            return;
+        }
+
+        // overlays only make sense once pixels are big enough to have gaps between them
+        let cell_w = view.width / src_w;
+        let cell_h = view.height / src_h;
+        let overlay = if cell_w >= 3 && cell_h >= 3 { self.overlay } else { Overlay::None };
+
+        for (y, row) in dst.chunks_exact_mut(dst_w).enumerate() {
+            if y < view.y || y >= view.y + view.height {
+                row.fill(self.border);
+                continue;
+            }
+
+            let sy = (y - view.y) * src_h / view.height;
+            let row_gap = match overlay {
+                Overlay::None => false,
+                // last row of each source pixel, plus the one before it for tall pixels
+                Overlay::Grid | Overlay::Scanlines => {
+                    let next = (sy + 1) * view.height / src_h;
+                    y + 1 == view.y + next || (cell_h >= 6 && y + 2 == view.y + next)
+                }
+            };
+
+            row[..view.x].fill(self.border);
+            row[view.x + view.width..].fill(self.border);
+            for (x, px) in row[view.x..view.x + view.width].iter_mut().enumerate() {
+                let sx = x * src_w / view.width;
+                let color = src[sy*src_w + sx];
+                let col_gap = overlay == Overlay::Grid && x + 1 == (sx + 1) * view.width / src_w;
+                *px = if row_gap || col_gap { darken(color) } else { color };
+            }
+        }
+    }
+}
+
+fn darken(color: u32) -> u32 {
+    (color >> 1) & 0x7F7F7F
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+pub const FRAME_RATE: u32 = 60; // timers tick once per frame
+pub const DEFAULT_CYCLES_PER_FRAME: u32 = 10; // 600 instructions per second
+
+const MAX_CATCH_UP_FRAMES: f64 = 4.0; // don't try to make up for long stalls (e.g. a hidden tab), at 1x
+pub const UNTHROTTLED_FRAMES: u32 = 16; // frames per host update when running as fast as possible
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Multiplier(f64), // 1.0 is real time, 2.0 is double speed, 0.5 is slow motion
+    Unthrottled,     // a fixed `UNTHROTTLED_FRAMES` per host update, i.e. 16x with a 60Hz host
+}
+
+impl Speed {
+    // accepts a multiplier such as `2`, `0.5` or `4x`, or `max` for unthrottled
+    pub fn parse(s: &str) -> Option<Speed> {
+        if s == "max" {
+            return Some(Speed::Unthrottled);
+        }
+
+        s.trim_end_matches('x')
+            .parse::<f64>()
+            .ok()
+            .filter(|m| *m > 0.0 && m.is_finite())
+            .map(Speed::Multiplier)
+    }
+}
+
+pub struct Scheduler {
+    pub cycles_per_frame: u32,
+    pub speed: Speed,
+    pub fast_forward_speed: Speed,
+    fast_forward: bool,
+    paused: bool,
+    pending_steps: u32, // frames requested with `advance_frame` while paused
+    frame_budget: f64,  // fractional frames owed to the emulator
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(DEFAULT_CYCLES_PER_FRAME)
+    }
+}
+
+impl Scheduler {
+    pub fn new(cycles_per_frame: u32) -> Scheduler {
+        Scheduler {
+            cycles_per_frame,
+            speed: Speed::Multiplier(1.0),
+            fast_forward_speed: Speed::Unthrottled,
+            fast_forward: false,
+            paused: false,
+            pending_steps: 0,
+            frame_budget: 0.0,
+        }
+    }
+
+    pub fn cycle_rate(&self) -> u32 {
+        self.cycles_per_frame * FRAME_RATE
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.frame_budget = 0.0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.set_paused(!self.paused);
+    }
+
+    // run exactly one more frame while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.pending_steps += 1;
+        }
+    }
+
+    pub fn is_fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    pub fn set_fast_forward(&mut self, enabled: bool) {
+        self.fast_forward = enabled;
+    }
+
+    pub fn current_speed(&self) -> Speed {
+        if self.fast_forward { self.fast_forward_speed } else { self.speed }
+    }
+
+    // number of emulated frames to run to keep up with `elapsed` wall time since the last call
+    pub fn frames_due(&mut self, elapsed: Duration) -> u32 {
+        if self.paused {
+            let steps = self.pending_steps;
+            self.pending_steps = 0;
+            return steps;
+        }
+
+        match self.current_speed() {
+            Speed::Unthrottled => {
+                self.frame_budget = 0.0;
+                UNTHROTTLED_FRAMES
+            }
+            Speed::Multiplier(multiplier) => {
+                let owed = self.frame_budget + elapsed.as_secs_f64() * FRAME_RATE as f64 * multiplier;
+                // a single update at Nx is owed N times as many frames, only stalls are capped
+                self.frame_budget = owed.min(MAX_CATCH_UP_FRAMES * multiplier.max(1.0));
+
+                let frames = self.frame_budget.floor();
+                self.frame_budget -= frames;
+                frames as u32
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::disasm;
+use crate::memory::{ADDR_MASK, MEM_SIZE};
+use crate::vm::VM;
+
+// an instruction that already ran being written over by the program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Patch {
+    pub pc: u16,     // the FX33 or FX55 doing the writing
+    pub opcode: u16,
+    pub addr: u16,   // the instruction written over
+    pub before: u16,
+    pub after: u16,
+}
+
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#05x} ({:04x}) wrote over the instruction at {:#05x}: {:04x} {} -> {:04x} {}",
+            self.pc, self.opcode, self.addr, self.before, disasm::disassemble(self.before), self.after, disasm::disassemble(self.after))
+    }
+}
+
+// finds self-modifying code: remembers which bytes have been executed, and reports
+// FX33 and FX55 writes that land on any of them
+pub struct SelfModDetector {
+    executed: Vec<bool>, // per address, whether it was part of an instruction that ran
+    starts: Vec<bool>,   // per address, whether an instruction that ran started there
+    pending: Vec<(u16, u16)>, // (address, opcode) of instructions about to be written over
+    patches: Vec<Patch>,
+    history: BTreeMap<(u16, u16), (u32, Patch)>, // by writer and address, how often and first before / last after
+}
+
+impl Default for SelfModDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SelfModDetector {
+    pub fn new() -> SelfModDetector {
+        SelfModDetector {
+            executed: vec![false; MEM_SIZE],
+            starts: vec![false; MEM_SIZE],
+            pending: Vec::new(),
+            patches: Vec::new(),
+            history: BTreeMap::new(),
+        }
+    }
+
+    // memory was reloaded, so nothing in it has run yet. patches found so far are kept
+    pub fn reset(&mut self) {
+        self.executed.fill(false);
+        self.starts.fill(false);
+        self.pending.clear();
+    }
+
+    // forgets everything, for a different program
+    pub fn clear(&mut self) {
+        *self = SelfModDetector::new();
+    }
+
+    // new patches since the last call, oldest first. see `summary` for all of them
+    pub fn take_patches(&mut self) -> Vec<Patch> {
+        std::mem::take(&mut self.patches)
+    }
+
+    // the instruction at PC is about to run
+    pub fn before(&mut self, vm: &VM) {
+        self.pending.clear();
+        let pc = vm.pc as usize;
+        if pc + 1 >= MEM_SIZE {
+            return;
+        }
+        self.executed[pc] = true;
+        self.executed[pc + 1] = true;
+        self.starts[pc] = true;
+
+        let opcode = vm.memory.get_instr(vm.pc);
+        let len = match opcode & 0xF0FF {
+            0xF033 => 3,
+            0xF055 => ((opcode & 0x0F00) >> 8) as usize + 1,
+            _ => return,
+        };
+        for addr in (vm.ir as usize..vm.ir as usize + len).map(|addr| addr & ADDR_MASK as usize) {
+            if !self.executed[addr] {
+                continue;
+            }
+            // the byte is the first or second half of an instruction, or both
+            for start in [addr.wrapping_sub(1), addr] {
+                let start = start & ADDR_MASK as usize;
+                if self.starts[start] && start + 1 < MEM_SIZE && !self.pending.iter().any(|(pending, _)| *pending as usize == start) {
+                    self.pending.push((start as u16, vm.memory.get_instr(start as u16)));
+                }
+            }
+        }
+    }
+
+    // the instruction `before` saw has run, `pc` and `opcode` being where it was and what
+    pub fn after(&mut self, vm: &VM, pc: u16, opcode: u16) {
+        for (addr, before) in self.pending.drain(..) {
+            let patch = Patch { pc, opcode, addr, before, after: vm.memory.get_instr(addr) };
+            let (count, summary) = self.history.entry((pc, addr)).or_insert((0, patch));
+            *count += 1;
+            summary.after = patch.after;
+            // like strict mode, each is only reported as it happens the first time
+            if *count == 1 {
+                self.patches.push(patch);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    // every instruction that wrote over code, with what it wrote over, how often, what was
+    // there at first and what it wrote the last time
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        for (count, patch) in self.history.values() {
+            let _ = writeln!(summary, "{}, {} {}", patch, count, if *count == 1 { "time" } else { "times" });
+        }
+        summary
+    }
+}
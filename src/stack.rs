@@ -0,0 +1,77 @@
+pub const MAX_STACK_DEPTH: usize = 16; // SCHIP and most later interpreters
+pub const VIP_STACK_DEPTH: usize = 12;  // the COSMAC VIP interpreter's stack area
+
+// return addresses for 2NNN/00EE in a fixed number of slots, like the real interpreters
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stack {
+    slots: [u16; MAX_STACK_DEPTH],
+    sp: usize,    // number of addresses on the stack
+    depth: usize, // slots available, up to MAX_STACK_DEPTH
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new(MAX_STACK_DEPTH)
+    }
+}
+
+impl Stack {
+    pub fn new(depth: usize) -> Stack {
+        Stack {
+            slots: [0; MAX_STACK_DEPTH],
+            sp: 0,
+            depth: depth.clamp(1, MAX_STACK_DEPTH),
+        }
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // clamped to 1..=MAX_STACK_DEPTH, anything above the new depth is dropped
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.clamp(1, MAX_STACK_DEPTH);
+        self.sp = self.sp.min(self.depth);
+    }
+
+    // false if the stack is full
+    pub fn push(&mut self, addr: u16) -> bool {
+        if self.sp == self.depth {
+            return false;
+        }
+        self.slots[self.sp] = addr;
+        self.sp += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u16> {
+        if self.sp == 0 {
+            return None;
+        }
+        self.sp -= 1;
+        Some(self.slots[self.sp])
+    }
+
+    pub fn clear(&mut self) {
+        self.sp = 0;
+    }
+
+    // the addresses on the stack, oldest first
+    pub fn as_slice(&self) -> &[u16] {
+        &self.slots[..self.sp]
+    }
+
+    // replaces the contents, false if they don't fit
+    pub fn set(&mut self, addrs: &[u16]) -> bool {
+        if addrs.len() > self.depth {
+            return false;
+        }
+        self.slots[..addrs.len()].copy_from_slice(addrs);
+        self.sp = addrs.len();
+        true
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+
+use crate::display::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use crate::memory::MEM_SIZE;
+use crate::quirks::Quirks;
+use crate::stack::MAX_STACK_DEPTH;
+use crate::vm::NUM_REGISTERS;
+
+// save states are a fixed size, which frontends such as libretro rely on for rewind and netplay
+const MAGIC: &[u8; 4] = b"RC8S";
+const VERSION: u8 = 2;
+
+pub const STACK_SLOTS: usize = MAX_STACK_DEPTH; // every slot is saved, used or not
+
+const HEADER_SIZE: usize = MAGIC.len() + 1;
+const VM_SIZE: usize = MEM_SIZE
+    + 2 + 2                         // pc, I
+    + 1 + 1 + STACK_SLOTS * 2       // stack size, pointer and slots
+    + 1 + 1                         // delay and sound timers
+    + DISPLAY_WIDTH * DISPLAY_HEIGHT
+    + NUM_REGISTERS
+    + 1;                            // quirks
+const EMULATOR_SIZE: usize = 4 + 4 + 1 + 1;
+
+pub const STATE_SIZE: usize = HEADER_SIZE + VM_SIZE + EMULATOR_SIZE;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,                // not a save state
+    UnsupportedVersion(u8),  // written by a different version of the emulator
+    Truncated,
+    StackTooDeep(usize),     // more nested calls than a state can hold
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "unsupported save state version {}", v),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::StackTooDeep(depth) => write!(f, "stack is {} deep, at most {} can be saved", depth, STACK_SLOTS),
+        }
+    }
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut buf = Vec::with_capacity(STATE_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        StateWriter { buf }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn quirks(&mut self, quirks: &Quirks) {
+        let flags = [
+            quirks.shift_uses_vy,
+            quirks.load_store_increments_i,
+            quirks.jump_uses_vx,
+            quirks.vf_reset,
+            quirks.clip_sprites,
+            quirks.display_wait,
+            quirks.index_overflow,
+        ];
+        self.u8(flags.iter().enumerate().fold(0, |bits, (i, on)| bits | (*on as u8) << i));
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    // checks the header, the rest is checked as it's read
+    pub fn new(buf: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if buf.len() < HEADER_SIZE || &buf[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        if buf[MAGIC.len()] != VERSION {
+            return Err(StateError::UnsupportedVersion(buf[MAGIC.len()]));
+        }
+
+        Ok(StateReader { buf: &buf[HEADER_SIZE..] })
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.buf.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, StateError> {
+        let b = self.bytes(4)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, StateError> {
+        let bits = self.u8()?;
+        let flag = |i: u8| bits & (1 << i) != 0;
+        Ok(Quirks {
+            shift_uses_vy: flag(0),
+            load_store_increments_i: flag(1),
+            jump_uses_vx: flag(2),
+            vf_reset: flag(3),
+            clip_sprites: flag(4),
+            display_wait: flag(5),
+            index_overflow: flag(6),
+        })
+    }
+}
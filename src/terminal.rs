@@ -0,0 +1,359 @@
+extern crate crossterm;
+
+use std::cell::Cell;
+use std::io::{self, Stdout, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags};
+
+use crate::disasm;
+use crate::display::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use crate::emulator::FrameInput;
+use crate::frontend::{AudioSink, InputSource, VideoSink};
+use crate::palette::Palette;
+use crate::vm::VM;
+
+// most terminals only report presses (and autorepeats), never releases. a key counts as
+// held until a little after its last press, longer for the first one to cover the delay
+// before autorepeat kicks in
+const KEY_HOLD: Duration = Duration::from_millis(100);
+const KEY_HOLD_FIRST: Duration = Duration::from_millis(550);
+const FRAME_TIME: Duration = Duration::from_millis(1000/60);
+const PANEL_ROWS: u16 = 20; // VM registers as printed by its Display impl, the stack goes beside them
+const PANEL_GAP: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Glyphs {
+    HalfBlock, // ▀▄█, 2 pixels per character
+    Braille,   // 2x4 pixels per character
+}
+
+impl Glyphs {
+    pub fn parse(s: &str) -> Option<Glyphs> {
+        match s {
+            "blocks" => Some(Glyphs::HalfBlock),
+            "braille" => Some(Glyphs::Braille),
+            _ => None,
+        }
+    }
+
+    // (columns, rows) the display takes up
+    pub fn size(&self) -> (u16, u16) {
+        match self {
+            Glyphs::HalfBlock => (DISPLAY_WIDTH as u16, DISPLAY_HEIGHT as u16 / 2),
+            Glyphs::Braille => (DISPLAY_WIDTH as u16 / 2, DISPLAY_HEIGHT as u16 / 4),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BeepStyle {
+    Bell,  // ring the terminal bell when the beep starts
+    Flash, // invert the screen while beeping
+}
+
+impl BeepStyle {
+    pub fn parse(s: &str) -> Option<BeepStyle> {
+        match s {
+            "bell" => Some(BeepStyle::Bell),
+            "flash" => Some(BeepStyle::Flash),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyState {
+    Up,
+    Until(Instant), // guessed, released at this time unless pressed again
+    Down,           // the terminal reports releases, so held until it says otherwise
+}
+
+fn rgb(color: u32) -> style::Color {
+    let [_, r, g, b] = color.to_be_bytes();
+    style::Color::Rgb { r, g, b }
+}
+
+fn keypad_key(c: char) -> Option<u8> {
+    match c {
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        '4' => Some(0xC),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'r' => Some(0xD),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'f' => Some(0xE),
+        'z' => Some(0xA),
+        'x' => Some(0x0),
+        'c' => Some(0xB),
+        'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+// renders into the terminal's alternate screen and reads keys from raw mode stdin
+pub struct TerminalFrontend {
+    pub glyphs: Glyphs,
+    pub panel: bool,            // show registers and disassembly next to the screen
+    pub palette: Option<Palette>, // background and foreground, otherwise the terminal's own colors
+    pub status: String,         // shown under the screen
+    out: Stdout,
+    keys: [KeyState; 16],
+    hotkeys: Vec<char>,         // other keys pressed since the last call to `take_hotkeys`
+    quit: bool,
+    reports_release: bool,
+    flash: Rc<Cell<bool>>,      // shared with the beeper in flash mode
+    flashed: bool,              // whether the last render was inverted
+    last: [u8; DISPLAY_WIDTH*DISPLAY_HEIGHT],
+}
+
+impl TerminalFrontend {
+    pub fn new(glyphs: Glyphs) -> io::Result<TerminalFrontend> {
+        let mut frontend = TerminalFrontend {
+            glyphs,
+            panel: false,
+            palette: None,
+            status: String::new(),
+            out: io::stdout(),
+            keys: [KeyState::Up; 16],
+            hotkeys: Vec::new(),
+            quit: false,
+            reports_release: false,
+            flash: Rc::new(Cell::new(false)),
+            flashed: false,
+            last: [0; DISPLAY_WIDTH*DISPLAY_HEIGHT],
+        };
+        frontend.resume()?;
+        Ok(frontend)
+    }
+
+    pub fn beeper(&self, style: BeepStyle) -> TerminalBeeper {
+        TerminalBeeper {
+            style,
+            on: false,
+            flash: self.flash.clone(),
+        }
+    }
+
+    // hands the terminal back, e.g. for the line based debugger
+    pub fn suspend(&mut self) -> io::Result<()> {
+        if self.reports_release {
+            execute!(self.out, event::PopKeyboardEnhancementFlags)?;
+        }
+        execute!(self.out, style::ResetColor, cursor::Show, terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()
+    }
+
+    pub fn resume(&mut self) -> io::Result<()> {
+        terminal::enable_raw_mode()?;
+        execute!(self.out, terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+
+        // terminals implementing the kitty keyboard protocol can tell us about releases
+        self.reports_release = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if self.reports_release {
+            execute!(self.out, event::PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        self.keys = [KeyState::Up; 16];
+        Ok(())
+    }
+
+    pub fn take_hotkeys(&mut self) -> Vec<char> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    pub fn draw_panel(&mut self, vm: &VM) {
+        if !self.panel {
+            return;
+        }
+
+        let column = self.glyphs.size().0 + PANEL_GAP;
+        let registers = format!("{}", vm);
+        let listing = disasm::disassemble_around(&vm.memory, vm.pc, 6, PANEL_ROWS - 6);
+        let registers: Vec<&str> = registers.lines().collect();
+        let mut listing = listing.iter();
+
+        for row in 0..PANEL_ROWS {
+            let line = |i: u16| registers.get(i as usize).copied().unwrap_or("");
+            let left = format!("{:<14}{:<14}", line(row), line(row + PANEL_ROWS));
+            let right = match listing.next() {
+                Some((addr, instr, text)) => {
+                    let marker = if *addr == vm.pc { '>' } else { ' ' };
+                    format!("{} {:03x}  {:04x}  {}", marker, addr, instr, text)
+                }
+                None => String::new(),
+            };
+            let _ = queue!(
+                self.out,
+                cursor::MoveTo(column, row),
+                style::Print(format!("{}{}", left, right)),
+                terminal::Clear(terminal::ClearType::UntilNewLine)
+            );
+        }
+        let _ = self.out.flush();
+    }
+
+    fn render(&mut self) {
+        let (_, rows) = self.glyphs.size();
+        let flash = self.flash.get();
+        // no shades in between, so filtered pixels show while they're at least half lit
+        let pixel = |x: usize, y: usize| self.last[y*DISPLAY_WIDTH + x] >= 0x80;
+
+        let _ = queue!(self.out, style::SetAttribute(if flash { style::Attribute::Reverse } else { style::Attribute::NoReverse }));
+        if let Some(palette) = self.palette {
+            let _ = queue!(self.out, style::SetColors(style::Colors::new(rgb(palette.color(1)), rgb(palette.background()))));
+        }
+        for row in 0..rows as usize {
+            let line: String = match self.glyphs {
+                Glyphs::HalfBlock => (0..DISPLAY_WIDTH)
+                    .map(|x| match (pixel(x, row*2), pixel(x, row*2 + 1)) {
+                        (false, false) => ' ',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (true, true) => '█',
+                    })
+                    .collect(),
+                Glyphs::Braille => (0..DISPLAY_WIDTH/2)
+                    .map(|col| {
+                        // dots are numbered down the left column, then the right, then the bottom row
+                        const DOTS: [(usize, usize, u32); 8] = [
+                            (0, 0, 0x01), (0, 1, 0x02), (0, 2, 0x04), (1, 0, 0x08),
+                            (1, 1, 0x10), (1, 2, 0x20), (0, 3, 0x40), (1, 3, 0x80),
+                        ];
+                        let bits = DOTS.iter()
+                            .filter(|(dx, dy, _)| pixel(col*2 + dx, row*4 + dy))
+                            .fold(0, |bits, (_, _, bit)| bits | bit);
+                        char::from_u32(0x2800 + bits).unwrap_or(' ')
+                    })
+                    .collect(),
+            };
+            let _ = queue!(self.out, cursor::MoveTo(0, row as u16), style::Print(line));
+        }
+        let _ = queue!(self.out, style::SetAttribute(style::Attribute::NoReverse), style::ResetColor);
+
+        let status_row = if self.panel { rows.max(PANEL_ROWS) } else { rows } + 1;
+        let _ = queue!(
+            self.out,
+            cursor::MoveTo(0, status_row),
+            style::Print(&self.status),
+            terminal::Clear(terminal::ClearType::UntilNewLine)
+        );
+        let _ = self.out.flush();
+        self.flashed = flash;
+    }
+
+    fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers, kind: KeyEventKind) {
+        let c = match code {
+            KeyCode::Esc => {
+                self.quit = true;
+                return;
+            }
+            // raw mode swallows the signal
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                self.quit = true;
+                return;
+            }
+            KeyCode::Tab => '\t',
+            KeyCode::Backspace => '\x08',
+            KeyCode::Char(c) => c.to_ascii_lowercase(),
+            _ => return,
+        };
+
+        match keypad_key(c) {
+            Some(key) => {
+                let key = key as usize;
+                self.keys[key] = match kind {
+                    KeyEventKind::Release => KeyState::Up,
+                    _ if self.reports_release => KeyState::Down,
+                    _ => {
+                        let hold = if self.keys[key] == KeyState::Up { KEY_HOLD_FIRST } else { KEY_HOLD };
+                        let until = Instant::now() + hold;
+                        match self.keys[key] {
+                            KeyState::Until(t) if t > until => KeyState::Until(t),
+                            _ => KeyState::Until(until),
+                        }
+                    }
+                };
+            }
+            None if kind == KeyEventKind::Press => self.hotkeys.push(c),
+            None => {}
+        }
+    }
+}
+
+impl Drop for TerminalFrontend {
+    fn drop(&mut self) {
+        let _ = self.suspend();
+    }
+}
+
+impl VideoSink for TerminalFrontend {
+    // always the 64x32 display, which the glyph layout is sized for
+    fn present(&mut self, frame: &[u8], _width: usize, _height: usize) {
+        self.last.copy_from_slice(frame);
+        self.render();
+    }
+
+    fn idle(&mut self) {
+        if self.flash.get() != self.flashed {
+            self.render();
+        }
+    }
+}
+
+impl InputSource for TerminalFrontend {
+    fn poll(&mut self) -> FrameInput {
+        // waiting for the first event also paces the main loop at about 60Hz
+        let mut timeout = FRAME_TIME;
+        while let Ok(true) = event::poll(timeout) {
+            timeout = Duration::ZERO;
+            if let Ok(Event::Key(key)) = event::read() {
+                self.handle_key(key.code, key.modifiers, key.kind);
+            }
+        }
+
+        let now = Instant::now();
+        let mut input = FrameInput::new();
+        for (key, state) in self.keys.iter_mut().enumerate() {
+            if let KeyState::Until(t) = *state {
+                if t <= now {
+                    *state = KeyState::Up;
+                }
+            }
+            input.set_key(key as u8, *state != KeyState::Up);
+        }
+
+        input
+    }
+
+    fn should_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+// the terminal can't play tones, so a beep rings the bell or flashes the screen
+pub struct TerminalBeeper {
+    pub style: BeepStyle,
+    on: bool,
+    flash: Rc<Cell<bool>>,
+}
+
+impl AudioSink for TerminalBeeper {
+    fn set_beep(&mut self, on: bool) {
+        match self.style {
+            BeepStyle::Bell if on && !self.on => {
+                print!("\x07");
+                let _ = io::stdout().flush();
+            }
+            BeepStyle::Flash => self.flash.set(on),
+            _ => {}
+        }
+        self.on = on;
+    }
+}
@@ -0,0 +1,72 @@
+// instruction timing for the original COSMAC VIP interpreter, in 1802 machine cycles
+// (8 clock cycles each at 1.76 MHz). figures follow published analyses of the
+// interpreter's code paths and are approximations where the cost depends on data.
+
+pub const VIP_CYCLES_PER_FRAME: i32 = 3668;   // machine cycles between two 60Hz interrupts
+pub const VIP_INTERRUPT_CYCLES: i32 = 1832;   // spent in the interrupt routine and display DMA
+pub const VIP_CPU_CYCLES_PER_FRAME: i32 = VIP_CYCLES_PER_FRAME - VIP_INTERRUPT_CYCLES;
+
+const FETCH_CYCLES: u32 = 68; // fetch, decode and dispatch, paid by every instruction
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimingMode {
+    Instructions, // every instruction costs the same, `cycles_per_frame` of them per frame
+    CosmacVip,    // each instruction costs its VIP machine cycles out of a fixed budget per frame
+}
+
+impl TimingMode {
+    pub fn parse(s: &str) -> Option<TimingMode> {
+        match s {
+            "instructions" => Some(TimingMode::Instructions),
+            "vip" => Some(TimingMode::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+// `registers` are the values before the instruction ran, `skipped` whether a skip was taken
+pub fn vip_instruction_cycles(instr: u16, registers: &[u8; 16], skipped: bool) -> u32 {
+    let x = ((instr & 0x0F00) >> 8) as usize;
+    let n = (instr & 0x000F) as u32;
+    let skip_cycles = if skipped { 4 } else { 0 };
+
+    let cycles = match instr & 0xF000 {
+        0x0000 => match instr {
+            0x00E0 => 24 + 3078, // clears all 256 bytes of display memory
+            0x00EE => 10,
+            _ => 10,             // machine code routine, whose own cost is unknown
+        },
+        0x1000 => 12,
+        0x2000 => 26,
+        0x3000 | 0x4000 => 10 + skip_cycles,
+        0x5000 | 0x9000 => 14 + skip_cycles,
+        0x6000 => 6,
+        0x7000 => 10,
+        0x8000 => 44,
+        0xA000 => 12,
+        0xB000 => 22,
+        0xC000 => 36,
+        0xD000 => {
+            // sprites that aren't byte aligned have to be shifted across two bytes
+            let per_row = if registers[x].is_multiple_of(8) { 46 } else { 68 };
+            26 + n * per_row
+        }
+        0xE000 => 14 + skip_cycles,
+        0xF000 => match instr & 0x00FF {
+            0x07 | 0x15 | 0x18 => 10,
+            0x0A => 19, // one poll of the keypad
+            0x1E => 16,
+            0x29 => 16,
+            0x33 => {
+                // repeated subtraction, so larger digits take longer
+                let vx = registers[x] as u32;
+                80 + (vx / 100 + vx / 10 % 10 + vx % 10) * 16
+            }
+            0x55 | 0x65 => 14 + (x as u32 + 1) * 14,
+            _ => 10,
+        },
+        _ => 10,
+    };
+
+    FETCH_CYCLES + cycles
+}
@@ -1,17 +1,26 @@
 use crate::memory::Memory;
-use crate::display::Display;
+use crate::display::{Display, DISPLAY_WIDTH, DISPLAY_HEIGHT};
 use crate::keypad::Keypad;
+use crate::quirks::Quirks;
+use crate::memory::{ADDR_MASK, MEM_SIZE};
+use crate::sanitizer::{Diagnostic, Sanitizer};
+use crate::coverage::Coverage;
+use crate::selfmod::{Patch, SelfModDetector};
+use crate::stack::Stack;
+use crate::state::{StateError, StateReader, StateWriter, STACK_SLOTS};
+use crate::error::VmError;
 
 use std::fmt::LowerHex;
 use num::Integer;
 use rand::Rng;
 
-const START_ADDR: usize = 0x200;
-const FONT_START_ADDR: usize = 0x50; 
-const FONT_END_ADDR: usize = 0xA0; 
-const NUM_REGISTERS: usize = 16; 
+pub const START_ADDR: usize = 0x200;
+pub const MAX_ROM_SIZE: usize = MEM_SIZE - START_ADDR;
+pub const FONT_START_ADDR: usize = 0x50; 
+pub const FONT_END_ADDR: usize = 0xA0; 
+pub const NUM_REGISTERS: usize = 16; 
 
-const FONT_HEIGHT: u8 = 5; // height (in pixels) that each digit of font occupies
+pub const FONT_HEIGHT: u8 = 5; // height (in pixels) that each digit of font occupies
 
 static FONTS: [u8; 16 * FONT_HEIGHT as usize] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -58,13 +67,24 @@ pub struct VM {
     pub memory: Memory, // 4kb RAM
     pub pc: u16,                // program counter, 2^12 = 4096
     pub ir: u16,                // index register
-    stack: Vec<u16>,        // should have 16 elements at any one time
+    pub stack: Stack,           // return addresses
     pub delay_t: u8,            // delay timer
     pub sound_t: u8,            // sound timer
     pub display: Display,       // display graphics
     pub registers: [u8; NUM_REGISTERS],   // 16 general-purpose registers
     keys: Keypad,
     pub redraw: bool,
+    pub quirks: Quirks,
+    pub rom: Vec<u8>,           // the program, loaded again by `reset`
+    pub sanitizer: Option<Sanitizer>, // strict mode, see `set_strict`
+    pub coverage: Option<Coverage>,   // see `set_coverage`
+    pub selfmod: Option<SelfModDetector>, // see `set_selfmod`
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
@@ -73,13 +93,18 @@ impl VM {
             memory: Memory::new(),
             pc: START_ADDR as u16,
             ir: 0,
-            stack: Vec::new(),
+            stack: Stack::default(),
             delay_t: 0,
             sound_t: 0,
             display: Display::new(),
             registers: [0; NUM_REGISTERS],
             keys: Keypad::new(),
             redraw: false,
+            quirks: Quirks::default(),
+            rom: Vec::new(),
+            sanitizer: None,
+            coverage: None,
+            selfmod: None,
         };
 
         // load fonts
@@ -98,12 +123,141 @@ impl VM {
         }
     }
 
-    pub fn load_program(&mut self, buf: &[u8]) {
-        self.memory.map_range(START_ADDR, buf.len(), buf);
+    // back to power on with the same program. quirks are kept
+    pub fn reset(&mut self) {
+        self.memory = Memory::new();
+        self.memory.map_range(FONT_START_ADDR, FONT_END_ADDR - FONT_START_ADDR, &FONTS);
+        self.memory.map_range(START_ADDR, self.rom.len(), &self.rom);
+        self.pc = START_ADDR as u16;
+        self.ir = 0;
+        self.stack.clear();
+        self.delay_t = 0;
+        self.sound_t = 0;
+        self.display.clear();
+        self.registers = [0; NUM_REGISTERS];
+        self.keys = Keypad::new();
+        self.redraw = true;
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.reset(&self.memory, self.rom.len());
+        }
+        if let Some(selfmod) = self.selfmod.as_mut() {
+            selfmod.reset();
+        }
+    }
+
+    // strict mode reports undefined behaviour as the program runs, see `take_diagnostics`.
+    // it only knows what was written to memory from when it's turned on
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.sanitizer = if enabled { Some(Sanitizer::new(&self.memory, self.rom.len())) } else { None };
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.sanitizer.is_some()
+    }
+
+    // problems found in strict mode since the last call
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.sanitizer.as_mut().map(Sanitizer::take_diagnostics).unwrap_or_default()
+    }
+
+    // counts what the program executes, reads and writes, see `Coverage`. the counts carry
+    // on across resets and are only cleared when a different program is loaded
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled { Some(Coverage::new()) } else { None };
+    }
+
+    // reports the program writing over its own instructions, see `take_patches`
+    pub fn set_selfmod(&mut self, enabled: bool) {
+        self.selfmod = if enabled { Some(SelfModDetector::new()) } else { None };
+    }
+
+    // instructions written over since the last call
+    pub fn take_patches(&mut self) -> Vec<Patch> {
+        self.selfmod.as_mut().map(SelfModDetector::take_patches).unwrap_or_default()
+    }
+
+    // resets with a new program, leaving the machine as it was if it doesn't fit
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), VmError> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(VmError::RomTooLarge(rom.len()));
+        }
+        self.rom = rom.to_vec();
+        self.reset();
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.clear();
+        }
+        if let Some(selfmod) = self.selfmod.as_mut() {
+            selfmod.clear();
+        }
+        Ok(())
+    }
+
+    // writes a new version of the program over the old one without resetting anything
+    // else, e.g. to try out an edit without losing your place. whatever was left of the
+    // old program past the end of the new one is cleared
+    pub fn patch_rom(&mut self, rom: &[u8]) -> Result<(), VmError> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(VmError::RomTooLarge(rom.len()));
+        }
+        if rom.len() < self.rom.len() {
+            let leftover = vec![0; self.rom.len() - rom.len()];
+            self.memory.map_range(START_ADDR + rom.len(), leftover.len(), &leftover);
+        }
+        self.memory.map_range(START_ADDR, rom.len(), rom);
+        self.rom = rom.to_vec();
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.load_program(&self.memory, rom.len());
+        }
+        if let Some(selfmod) = self.selfmod.as_mut() {
+            // none of the new code has run
+            selfmod.reset();
+        }
+        Ok(())
     }
 
     pub fn get_display(&self) -> &[u8] {
-        return &self.display.pixels; 
+        &self.display.pixels
+    }
+
+    // held keys and the redraw flag are left out, they're refreshed every frame
+    pub fn save_state(&self, w: &mut StateWriter) -> Result<(), StateError> {
+        w.bytes(self.memory.get_range(0, MEM_SIZE));
+        w.u16(self.pc);
+        w.u16(self.ir);
+        w.u8(self.stack.depth() as u8);
+        w.u8(self.stack.sp() as u8);
+        for i in 0..STACK_SLOTS {
+            w.u16(self.stack.as_slice().get(i).copied().unwrap_or(0));
+        }
+        w.u8(self.delay_t);
+        w.u8(self.sound_t);
+        w.bytes(&self.display.pixels);
+        w.bytes(&self.registers);
+        w.quirks(&self.quirks);
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.map_range(0, MEM_SIZE, r.bytes(MEM_SIZE)?);
+        self.pc = r.u16()?;
+        self.ir = r.u16()?;
+        self.stack.set_depth(r.u8()? as usize);
+        let sp = r.u8()? as usize;
+        let mut slots = [0; STACK_SLOTS];
+        for slot in slots.iter_mut() {
+            *slot = r.u16()?;
+        }
+        if !self.stack.set(&slots[..sp.min(STACK_SLOTS)]) || sp > STACK_SLOTS {
+            return Err(StateError::StackTooDeep(sp));
+        }
+        self.delay_t = r.u8()?;
+        self.sound_t = r.u8()?;
+        self.display.pixels.copy_from_slice(r.bytes(DISPLAY_WIDTH*DISPLAY_HEIGHT)?);
+        self.display.invalidate();
+        self.registers.copy_from_slice(r.bytes(NUM_REGISTERS)?);
+        self.quirks = r.quirks()?;
+        self.redraw = true;
+        Ok(())
     }
 
     pub fn decrement_timers(&mut self) -> bool {
@@ -119,7 +273,26 @@ impl VM {
         false
     }
 
-    pub fn emulate_cycle(&mut self) {
+    // returns the instruction that ran. on an error the instruction has no effect, and PC
+    // stays on it
+    pub fn emulate_cycle(&mut self) -> Result<u16, VmError> {
+        if let Some(mut sanitizer) = self.sanitizer.take() {
+            sanitizer.check(self);
+            self.sanitizer = Some(sanitizer);
+        }
+        if let Some(mut coverage) = self.coverage.take() {
+            coverage.record(self);
+            self.coverage = Some(coverage);
+        }
+        if let Some(mut selfmod) = self.selfmod.take() {
+            selfmod.before(self);
+            self.selfmod = Some(selfmod);
+        }
+
+        let pc = self.pc;
+        if pc as usize + 1 >= MEM_SIZE {
+            return Err(VmError::PcOutOfRange(pc));
+        }
         let instr = self.memory.get_instr(self.pc);
         // println!("{:#x}", instr);
 
@@ -134,7 +307,10 @@ impl VM {
 
         match opcode {
             0x0 => { 
-                self.handle_0x0(nnn);
+                if !self.handle_0x0(nnn) {
+                    self.pc = pc;
+                    return Err(VmError::StackUnderflow(pc));
+                }
             }
             0x1000 => {
                 // jump
@@ -142,7 +318,10 @@ impl VM {
             }
             0x2000 => {
                 // subroutine call
-                self.stack.push(self.pc);
+                if !self.stack.push(self.pc) {
+                    self.pc = pc;
+                    return Err(VmError::StackOverflow(pc));
+                }
                 self.pc = nnn;
             }
             0x3000 => {
@@ -170,7 +349,10 @@ impl VM {
             }
             0x8000 => {
                 // arithmetic
-                self.handle_0x8(x, y, n);
+                if !self.handle_0x8(x, y, n) {
+                    self.pc = pc;
+                    return Err(VmError::InvalidOpcode(pc, instr));
+                }
             }
             0x9000 => {
                 let vx = self.registers[x];
@@ -182,9 +364,9 @@ impl VM {
                 self.ir = nnn;
             }
             0xB000 => {
-                // jmp w/ offset (following impl of COSMAC VIP interpreter)
-                // TODO: support toggle to behavior where self.registers[x] is used
-                self.pc = nnn + self.registers[0] as u16;
+                // jmp w/ offset (COSMAC VIP uses v0, CHIP-48 uses vx)
+                let offset = if self.quirks.jump_uses_vx { self.registers[x] } else { self.registers[0] };
+                self.pc = nnn + offset as u16;
             }
             0xC000 => {
                 // random num AND -> vx
@@ -196,25 +378,42 @@ impl VM {
                 let vx = self.registers[x] as usize; 
                 let vy = self.registers[y] as usize;
                 // get memory[ir..ir+sprite_height]
-                let sprite: &[u8] = self.memory.get_range(self.ir as usize, sprite_height);
+                let mut sprite = [0; 15];
+                self.memory.read_wrapping(self.ir, &mut sprite[..sprite_height]);
 
-                let collide_flag: u8 = self.display.draw(vx, vy, sprite_height, sprite);
+                let collide_flag: u8 = self.display.draw(vx, vy, sprite_height, &sprite, self.quirks.clip_sprites);
                 self.redraw = true;
 
                 self.registers[0xF] = collide_flag;
             }
             0xE000 => {
                 // skip if key press
-                self.handle_0xe(x, nn);
+                if !self.handle_0xe(x, nn) {
+                    self.pc = pc;
+                    return Err(VmError::InvalidOpcode(pc, instr));
+                }
             }
             0xF000 => {
-                self.handle_0xf(x, nn);
+                if !self.handle_0xf(x, nn) {
+                    self.pc = pc;
+                    return Err(VmError::InvalidOpcode(pc, instr));
+                }
+            }
+            _ => {
+                self.pc = pc;
+                return Err(VmError::InvalidOpcode(pc, instr));
             }
-            _ =>  { panic!("Unknown code {:#x}", opcode); }
         }
+
+        if let Some(mut selfmod) = self.selfmod.take() {
+            selfmod.after(self, pc, instr);
+            self.selfmod = Some(selfmod);
+        }
+        Ok(instr)
     }
 
-    fn handle_0x0(&mut self, nnn: u16) {
+    // false if there was nothing to return to
+    fn handle_0x0(&mut self, nnn: u16) -> bool {
         match nnn {
             0x0E0 => {
                 // clear display
@@ -223,26 +422,32 @@ impl VM {
             }
             0x0EE => {
                 // return from subroutine
-                self.pc = self.stack.pop().unwrap();
+                match self.stack.pop() {
+                    Some(addr) => self.pc = addr,
+                    None => return false,
+                }
             }
             _ => ()
         }
+        true
     }
 
-    fn handle_0x8(&mut self, x: usize, y: usize, n: u8) {
+    // false for opcodes CHIP-8 doesn't define, which are left alone
+    fn handle_0x8(&mut self, x: usize, y: usize, n: u8) -> bool {
         match n {
             0 => {
                 // set vx to vy 
                 self.registers[x] = self.registers[y];
             }
-            1 => {
-                self.registers[x] |= self.registers[y];
-            }
-            2 => {
-                self.registers[x] &= self.registers[y];
-            }
-            3 => {
-                self.registers[x] ^= self.registers[y];
+            1..=3 => {
+                match n {
+                    1 => self.registers[x] |= self.registers[y],
+                    2 => self.registers[x] &= self.registers[y],
+                    _ => self.registers[x] ^= self.registers[y],
+                }
+                if self.quirks.vf_reset {
+                    self.registers[0xF] = 0;
+                }
             }
             4 => {
                 let sum: u16 = self.registers[x] as u16 + self.registers[y] as u16;
@@ -250,30 +455,31 @@ impl VM {
                 self.registers[0xF] = if sum > 255 {1} else {0}; // check for overflow;
             }
             5 | 7 => {
-                let (left, right): (u8, u8) = match n {
-                    5 => (self.registers[x], self.registers[y]),
-                    7 => (self.registers[y], self.registers[x]),
-                    _ => unreachable!(),
+                let (left, right): (u8, u8) = if n == 5 {
+                    (self.registers[x], self.registers[y])
+                } else {
+                    (self.registers[y], self.registers[x])
                 };
                 let (diff, underflow) = left.overflowing_sub(right);
                 self.registers[x] = diff;
                 self.registers[0xF] = if underflow {0} else {1};
             }
             6 | 0xE => {
-                let (new_val, flag_set) = match n {
-                    6 =>   (self.registers[x] >> 1, self.registers[x] & 0x1),
-                    0xE => (self.registers[x] << 1, (self.registers[x] >> 7) & 0x1),
-                    _ => unreachable!(),
+                let src = if self.quirks.shift_uses_vy { self.registers[y] } else { self.registers[x] };
+                let (new_val, flag_set) = if n == 6 {
+                    (src >> 1, src & 0x1)
+                } else {
+                    (src << 1, (src >> 7) & 0x1)
                 };
                 self.registers[x] = new_val;
                 self.registers[0xF] = flag_set; 
             }
-            _ => unreachable!()
-                
+            _ => return false,
         }
+        true
     }
 
-    fn handle_0xe(&mut self, x: usize, nn: u8) {
+    fn handle_0xe(&mut self, x: usize, nn: u8) -> bool {
         match nn {
             0x9E =>  {
                 if self.keys.is_pressed(self.registers[x]) {
@@ -285,11 +491,12 @@ impl VM {
                     self.pc += 2;
                 }
             }
-            _ => unreachable!()
+            _ => return false,
         }
+        true
     }
 
-    fn handle_0xf(&mut self, x: usize, nn: u8) {
+    fn handle_0xf(&mut self, x: usize, nn: u8) -> bool {
         match nn {
             0x07 => {
                 self.registers[x] = self.delay_t;
@@ -309,30 +516,37 @@ impl VM {
                 self.sound_t = self.registers[x];
             }
             0x1E => {
-                // TODO: Spaceflight 209! relies on overflow to cause VF=1
-                self.ir += self.registers[x] as u16; 
+                // Spaceflight 2091! relies on the Amiga interpreter setting VF on overflow
+                let sum = self.ir.wrapping_add(self.registers[x] as u16);
+                if self.quirks.index_overflow {
+                    self.registers[0xF] = (sum > ADDR_MASK) as u8;
+                }
+                self.ir = sum & ADDR_MASK;
             }
             0x29 => {
-                // point to font character
-                self.ir = FONT_START_ADDR as u16 + (FONT_HEIGHT * self.registers[x]) as u16;
+                // point to font character, only the low digit of VX counts
+                self.ir = FONT_START_ADDR as u16 + FONT_HEIGHT as u16 * (self.registers[x] & 0xF) as u16;
             }
             0x33 => {
                 // binary coded decimal conv
                 let digit: u8 = self.registers[x];
-                self.memory.set(self.ir, (digit/100) % 10);
-                self.memory.set(self.ir+1, (digit/10) % 10);
-                self.memory.set(self.ir+2, digit % 10);
+                self.memory.write_wrapping(self.ir, &[(digit/100) % 10, (digit/10) % 10, digit % 10]);
             }
             0x55 => {
-                let i = self.ir as usize;
-                self.memory.map_range(i, x+1, &self.registers[0..=x as usize]);
+                self.memory.write_wrapping(self.ir, &self.registers[0..=x]);
+                if self.quirks.load_store_increments_i {
+                    self.ir = (self.ir + x as u16 + 1) & ADDR_MASK;
+                }
             }
             0x65 => {
-                let i = self.ir as usize;
-                self.registers[0..=x as usize].copy_from_slice(self.memory.get_range(i, x+1));
+                self.memory.read_wrapping(self.ir, &mut self.registers[0..=x]);
+                if self.quirks.load_store_increments_i {
+                    self.ir = (self.ir + x as u16 + 1) & ADDR_MASK;
+                }
             }
-            _ => { unreachable!(); }
+            _ => return false,
         }
+        true
     }
 }
 
@@ -351,6 +565,11 @@ impl std::fmt::Display for VM {
         }
         vm_debug_info.push(get_line_debug("ST", self.sound_t));
         vm_debug_info.push(get_line_debug("DT", self.delay_t));
+        vm_debug_info.push(format!("SP:    {}/{}", self.stack.sp(), self.stack.depth()));
+        // most recent call first
+        for (i, addr) in self.stack.as_slice().iter().enumerate().rev() {
+            vm_debug_info.push(get_line_debug(format!("S{i}").as_ref(), *addr));
+        }
         fmt.write_str(&vm_debug_info.join("\n")[..]).unwrap();
         Ok(())
     }
@@ -1,17 +1,65 @@
 extern crate console_error_panic_hook;
 
 use wasm_bindgen::prelude::*;
-use crate::vm::VM;
+use crate::audio::{AudioGenerator, Waveform};
+use crate::disasm;
+use crate::display::{Rect, DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use crate::emulator::{Emulator, FrameInput, FrameOutput};
+use crate::filter::Filter;
+use crate::memory::{ADDR_MASK, MEM_SIZE};
+use crate::palette::Palette;
+use crate::postfx::{self, PostFx};
+use crate::profiler::Profiler;
+use crate::romdb::Platform;
+use crate::scaler::Scaler;
+use crate::scheduler::Speed;
+use crate::timing::TimingMode;
+use crate::vm::NUM_REGISTERS;
 
-use js_sys::{Uint8Array};
+use std::time::Duration;
+
+use js_sys::{Array, Float32Array, Uint8Array, Uint8ClampedArray};
 
 #[cfg(feature = "wee_alloc")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+// the module's memory, for viewing buffers through `display_ptr` and `rgba_ptr` without
+// copying them. views have to be recreated after memory grows
+#[wasm_bindgen]
+pub fn wasm_memory() -> JsValue {
+    wasm_bindgen::memory()
+}
+
+// area of the display that changed, in pixels
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl From<Rect> for DirtyRect {
+    fn from(rect: Rect) -> Self {
+        DirtyRect { x: rect.x, y: rect.y, width: rect.width, height: rect.height }
+    }
+}
+
 #[wasm_bindgen]
 pub struct WasmVM {
-    vm: VM,
+    emulator: Emulator,
+    input: FrameInput,
+    output: FrameOutput, // result of the last `update`
+    palette: Option<Palette>, // chosen by the user, otherwise the ROM's or the default
+    rgba: Vec<u8>,
+    rendered: Option<(Palette, Filter)>, // what `rgba` was last refreshed with
+    scaler: Scaler,
+    postfx: PostFx,
+    pixels: Vec<u32>, // the display in palette colors, then scaled for post-processing
+    scaled: Vec<u32>,
+    scaled_rgba: Vec<u8>,
 }
 
 extern crate web_sys;
@@ -31,12 +79,47 @@ impl WasmVM {
         console_error_panic_hook::set_once();
 
         WasmVM {
-            vm: VM::new(),
+            emulator: Emulator::new(),
+            input: FrameInput::new(),
+            output: FrameOutput::default(),
+            palette: None,
+            rgba: vec![0; DISPLAY_WIDTH*DISPLAY_HEIGHT*4],
+            rendered: None,
+            scaler: Scaler::new(),
+            postfx: PostFx::default(),
+            pixels: vec![0; DISPLAY_WIDTH*DISPLAY_HEIGHT],
+            scaled: Vec::new(),
+            scaled_rgba: Vec::new(),
+        }
+    }
+
+    // runs the frames due after `elapsed_ms` of wall time, returns whether to redraw.
+    // hitting a breakpoint or an error pauses, see `breakpoint` and `error`
+    pub fn update(&mut self, elapsed_ms: f64) -> bool {
+        let elapsed = Duration::from_secs_f64(elapsed_ms.max(0.0) / 1000.0);
+        self.output = self.emulator.update(elapsed, &self.input);
+        if self.output.breakpoint.is_some() || self.output.error.is_some() {
+            self.emulator.scheduler.set_paused(true);
         }
+        self.output.redraw
+    }
+
+    // the address execution stopped at during the last `update`, if it hit a breakpoint
+    pub fn breakpoint(&self) -> Option<u16> {
+        self.output.breakpoint
+    }
+
+    // why execution stopped during the last `update`, if an instruction couldn't run
+    pub fn error(&self) -> Option<String> {
+        self.output.error.map(|err| err.to_string())
     }
 
     pub fn should_redraw(&self) -> bool {
-        self.vm.redraw
+        self.output.redraw
+    }
+
+    pub fn sound_on(&self) -> bool {
+        self.output.sound
     }
 
     pub fn set_key(&mut self, key: &str, pressed: bool) {
@@ -62,25 +145,460 @@ impl WasmVM {
         };
 
 
-        if !key_mapped.is_none() {
-            self.vm.set_key(key_mapped, pressed);
+        if let Some(key) = key_mapped {
+            self.input.set_key(key, pressed);
+        }
+    }
+
+    // resets the machine with a new program, so one WasmVM can run any number of ROMs.
+    // throws if the ROM doesn't fit in memory
+    pub fn load_program(&mut self, buf: &[u8]) -> Result<(), JsValue> {
+        self.emulator.load_program(buf).map_err(|e| JsValue::from(e.to_string()))?;
+        self.output = FrameOutput::default();
+        Ok(())
+    }
+
+    // starts the program over, keeping quirks and other settings
+    pub fn reset(&mut self) {
+        self.emulator.reset();
+        self.output = FrameOutput::default();
+    }
+
+    // runs a single instruction, returns whether to redraw
+    pub fn step(&mut self) -> bool {
+        self.output = self.emulator.step(&self.input);
+        self.output.redraw
+    }
+
+    pub fn registers(&self) -> Uint8Array {
+        Uint8Array::from(&self.emulator.vm.registers[..])
+    }
+
+    pub fn set_register(&mut self, index: usize, value: u8) -> bool {
+        if index >= NUM_REGISTERS {
+            return false;
+        }
+        self.emulator.vm.registers[index] = value;
+        true
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.emulator.vm.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) -> bool {
+        if pc as usize >= MEM_SIZE - 1 {
+            return false;
+        }
+        self.emulator.vm.pc = pc;
+        true
+    }
+
+    // the I register
+    pub fn index(&self) -> u16 {
+        self.emulator.vm.ir
+    }
+
+    pub fn set_index(&mut self, index: u16) {
+        // I is 12 bits like the addresses it points at
+        self.emulator.vm.ir = index & ADDR_MASK;
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.emulator.vm.delay_t
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.emulator.vm.delay_t = value;
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.emulator.vm.sound_t
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.emulator.vm.sound_t = value;
+    }
+
+    // return addresses, oldest first
+    pub fn stack(&self) -> Vec<u16> {
+        self.emulator.vm.stack.as_slice().to_vec()
+    }
+
+    pub fn set_stack(&mut self, stack: &[u16]) -> bool {
+        self.emulator.vm.stack.set(stack)
+    }
+
+    pub fn stack_depth(&self) -> usize {
+        self.emulator.vm.stack.depth()
+    }
+
+    // from 1 to 16 slots, addresses that no longer fit are dropped
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.emulator.vm.stack.set_depth(depth);
+    }
+
+    // up to `len` bytes starting at `addr`, fewer past the end of memory
+    pub fn read_memory(&self, addr: usize, len: usize) -> Uint8Array {
+        let start = addr.min(MEM_SIZE);
+        let len = len.min(MEM_SIZE - start);
+        Uint8Array::from(self.emulator.vm.memory.get_range(start, len))
+    }
+
+    // fails without writing anything if the bytes don't fit
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> bool {
+        if addr + bytes.len() > MEM_SIZE {
+            return false;
+        }
+        self.emulator.vm.memory.map_range(addr, bytes.len(), bytes);
+        if let Some(sanitizer) = self.emulator.vm.sanitizer.as_mut() {
+            sanitizer.mark_written(addr, bytes.len());
+        }
+        true
+    }
+
+    // strict mode, reporting undefined behaviour through `take_diagnostics`
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.emulator.vm.set_strict(enabled);
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.emulator.vm.is_strict()
+    }
+
+    // problems found since the last call, as "pc (opcode): what happened"
+    pub fn take_diagnostics(&mut self) -> Vec<JsValue> {
+        self.emulator.vm.take_diagnostics().iter().map(|d| JsValue::from(d.to_string())).collect()
+    }
+
+    // reports the program writing over instructions it already ran, see `take_patches`
+    pub fn set_selfmod(&mut self, enabled: bool) {
+        self.emulator.vm.set_selfmod(enabled);
+    }
+
+    // new patches since the last call, as "pc (opcode) wrote over the instruction at ..."
+    pub fn take_patches(&mut self) -> Vec<JsValue> {
+        self.emulator.vm.take_patches().iter().map(|p| JsValue::from(p.to_string())).collect()
+    }
+
+    // every patch so far, with how often it happened
+    pub fn selfmod_summary(&self) -> Option<String> {
+        self.emulator.vm.selfmod.as_ref().map(|selfmod| selfmod.summary())
+    }
+
+    // counts what the program executes, reads and writes, see `coverage_json`
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.emulator.vm.set_coverage(enabled);
+    }
+
+    // the coverage report as JSON, if coverage is on
+    pub fn coverage_json(&self) -> Option<String> {
+        let vm = &self.emulator.vm;
+        vm.coverage.as_ref().map(|coverage| coverage.to_json(&vm.memory))
+    }
+
+    // profiles where the program spends its time, see `profile_report`
+    pub fn set_profiler(&mut self, enabled: bool) {
+        self.emulator.profiler = if enabled { Some(Profiler::new()) } else { None };
+    }
+
+    // hot spots, time per subroutine and time spent drawing and waiting for keys
+    pub fn profile_report(&self) -> Option<String> {
+        self.emulator.profiler.as_ref().map(|profiler| profiler.report(&self.emulator.vm.memory))
+    }
+
+    // the profile as collapsed stacks for flame graph tools, weighted by cycles in VIP timing
+    pub fn profile_collapsed(&self) -> Option<String> {
+        let vip = self.emulator.timing == TimingMode::CosmacVip;
+        self.emulator.profiler.as_ref().map(|profiler| profiler.collapsed(vip))
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.emulator.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.emulator.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.emulator.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> Vec<u16> {
+        let mut breakpoints: Vec<u16> = self.emulator.breakpoints.iter().copied().collect();
+        breakpoints.sort_unstable();
+        breakpoints
+    }
+
+    // `count` instructions from `addr`, as [address, opcode, text] arrays
+    pub fn disassemble(&self, addr: u16, count: u16) -> Array {
+        disasm::disassemble_around(&self.emulator.vm.memory, addr, 0, count)
+            .into_iter()
+            .map(|(addr, instr, text)| Array::of3(&addr.into(), &instr.into(), &text.into()))
+            .collect()
+    }
+
+    pub fn save_state(&self) -> Result<Vec<u8>, JsValue> {
+        self.emulator.save_state().map_err(|e| JsValue::from(e.to_string()))
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsValue> {
+        self.emulator.load_state(state).map_err(|e| JsValue::from(e.to_string()))
+    }
+
+    // a quirk by its field name in `Quirks`, e.g. "vf_reset"
+    pub fn quirk(&self, name: &str) -> Option<bool> {
+        self.emulator.vm.quirks.get(name)
+    }
+
+    pub fn set_quirk(&mut self, name: &str, enabled: bool) -> bool {
+        self.emulator.vm.quirks.set(name, enabled)
+    }
+
+    // all quirks and the stack depth as on vip, chip48 or modern
+    pub fn set_platform(&mut self, platform: &str) -> bool {
+        match Platform::parse(platform) {
+            Some(platform) => {
+                self.emulator.vm.quirks = platform.quirks();
+                self.emulator.vm.stack.set_depth(platform.stack_depth());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn rom_title(&self) -> Option<String> {
+        self.emulator.rom_info().map(|info| info.title.to_string())
+    }
+
+    pub fn rom_author(&self) -> Option<String> {
+        self.emulator.rom_info().map(|info| info.author.to_string())
+    }
+
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.emulator.scheduler.cycles_per_frame
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.emulator.scheduler.cycles_per_frame = cycles.max(1);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.emulator.scheduler.is_paused()
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.emulator.scheduler.set_paused(paused);
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.emulator.scheduler.toggle_pause();
+    }
+
+    pub fn advance_frame(&mut self) {
+        self.emulator.scheduler.advance_frame();
+    }
+
+    // takes a multiplier such as "2" or "0.5", or "max" to run unthrottled
+    pub fn set_speed(&mut self, speed: &str) -> bool {
+        match Speed::parse(speed) {
+            Some(speed) => {
+                self.emulator.scheduler.speed = speed;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_fast_forward_speed(&mut self, speed: &str) -> bool {
+        match Speed::parse(speed) {
+            Some(speed) => {
+                self.emulator.scheduler.fast_forward_speed = speed;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // "instructions" for a fixed number of instructions per frame, "vip" for COSMAC VIP timing
+    pub fn set_timing(&mut self, timing: &str) -> bool {
+        match TimingMode::parse(timing) {
+            Some(timing) => {
+                self.emulator.timing = timing;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_fast_forward(&mut self, enabled: bool) {
+        self.emulator.scheduler.set_fast_forward(enabled);
+    }
+
+    // starts generating PCM at the AudioContext's sample rate, drained with `take_audio`
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        self.emulator.audio = Some(AudioGenerator::new(sample_rate));
+    }
+
+    // mono samples generated since the last call
+    pub fn take_audio(&mut self) -> Float32Array {
+        match self.emulator.audio.as_mut() {
+            Some(generator) => Float32Array::from(&generator.take_samples()[..]),
+            None => Float32Array::new_with_length(0),
+        }
+    }
+
+    pub fn set_waveform(&mut self, waveform: &str) -> bool {
+        match (Waveform::parse(waveform), self.emulator.audio.as_mut()) {
+            (Some(waveform), Some(generator)) => {
+                generator.waveform = waveform;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        if let Some(generator) = self.emulator.audio.as_mut() {
+            generator.frequency = frequency.max(1.0);
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        if let Some(generator) = self.emulator.audio.as_mut() {
+            generator.volume = volume.clamp(0.0, 1.0);
+        }
+    }
+
+    // off, blend, phosphor (optionally phosphor:<frames>) or stable
+    pub fn set_filter(&mut self, filter: &str) -> bool {
+        match Filter::parse(filter) {
+            Some(filter) => {
+                self.emulator.filter.set_mode(filter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // a theme (classic, amber, lcd, octo), comma separated hex colors, or "auto" for the ROM's own
+    pub fn set_palette(&mut self, palette: &str) -> bool {
+        if palette == "auto" {
+            self.palette = None;
+            return true;
+        }
+
+        match Palette::parse(palette) {
+            Some(palette) => {
+                self.palette = Some(palette);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the palette in use as css colors, background first
+    pub fn palette(&self) -> Vec<JsValue> {
+        self.current_palette().colors.iter().map(|c| JsValue::from(format!("#{:06x}", c))).collect()
+    }
+
+    // the display as 64x32 RGBA pixels, ready for ImageData
+    pub fn get_rgba(&mut self) -> Uint8ClampedArray {
+        let palette = self.current_palette();
+        palette.render_rgba(self.emulator.frame(), &mut self.rgba);
+        Uint8ClampedArray::from(&self.rgba[..])
+    }
+
+    // off, scanlines, crt or glow
+    pub fn set_postfx(&mut self, preset: &str) -> bool {
+        match PostFx::preset(preset) {
+            Some(postfx) => {
+                self.postfx = postfx;
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn load_program(&mut self, buf: &[u8]) {
-        self.vm.load_program(buf);
+    pub fn postfx_presets(&self) -> Vec<JsValue> {
+        postfx::PRESETS.iter().map(|name| JsValue::from(*name)).collect()
+    }
+
+    // the display scaled to `width`x`height` with post-processing applied, as RGBA pixels
+    // ready for ImageData. more work than `get_rgba`, so only worth it with effects on
+    pub fn render(&mut self, width: usize, height: usize) -> Uint8ClampedArray {
+        let palette = self.current_palette();
+        palette.render_rgb(self.emulator.frame(), &mut self.pixels);
+
+        self.scaled.resize(width * height, 0);
+        self.scaled_rgba.resize(width * height * 4, 0);
+        let resolution = self.emulator.resolution();
+        self.scaler.render(&self.pixels, resolution, &mut self.scaled, (width, height));
+        self.postfx.apply(&mut self.scaled, (width, height));
+        postfx::to_rgba(&self.scaled, &mut self.scaled_rgba);
+        Uint8ClampedArray::from(&self.scaled_rgba[..])
+    }
+
+    // one byte per pixel, DISPLAY_WIDTH*DISPLAY_HEIGHT of them, in wasm memory
+    pub fn display_ptr(&self) -> *const u8 {
+        self.emulator.vm.display.as_ptr()
     }
 
-    pub fn emulate_cycle(&mut self) {
-        self.vm.emulate_cycle();
+    pub fn display_len(&self) -> usize {
+        self.emulator.get_display().len()
+    }
+
+    // RGBA pixels kept up to date by `refresh_rgba`, in wasm memory
+    pub fn rgba_ptr(&self) -> *const u8 {
+        self.rgba.as_ptr()
+    }
+
+    pub fn rgba_len(&self) -> usize {
+        self.rgba.len()
+    }
+
+    // brings the buffer behind `rgba_ptr` up to date, returning the area that changed so
+    // only that needs to go to the page, or nothing if the picture is the same. `all` redoes
+    // the whole picture regardless
+    pub fn refresh_rgba(&mut self, all: bool) -> Option<DirtyRect> {
+        let palette = self.current_palette();
+        let filter = self.emulator.filter.mode();
+        let dirty = self.emulator.vm.display.take_dirty();
+
+        // filters change pixels that weren't drawn to, so those redo the whole picture
+        let full = Rect { x: 0, y: 0, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT };
+        let rect = if all || self.rendered != Some((palette, filter)) {
+            full
+        } else if filter != Filter::Off {
+            if !self.output.redraw {
+                return None;
+            }
+            full
+        } else {
+            dirty?
+        };
+        self.rendered = Some((palette, filter));
+
+        let levels = self.emulator.frame();
+        for y in rect.y..rect.y + rect.height {
+            let row = y*DISPLAY_WIDTH + rect.x..y*DISPLAY_WIDTH + rect.x + rect.width;
+            let out = &mut self.rgba[row.start*4..row.end*4];
+            palette.render_rgba(&levels[row], out);
+        }
+
+        Some(rect.into())
     }
 
-    pub fn decrement_timers(&mut self) -> bool {
-        self.vm.decrement_timers()
+    fn current_palette(&self) -> Palette {
+        self.palette
+            .or_else(|| self.emulator.rom_info().and_then(|info| info.palette))
+            .unwrap_or_default()
     }
 
+    // copies, see `display_ptr` for a view instead
     pub fn get_display(&self) -> Uint8Array {
-        let display = self.vm.get_display();
+        let display = self.emulator.get_display();
         Uint8Array::from(display)
     }
 }
@@ -0,0 +1,66 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+struct WatchedFile {
+    path: PathBuf,
+    modified: Option<SystemTime>, // as of the last reported change
+    pending: Option<SystemTime>,  // seen changed, waiting for writes to settle
+}
+
+// notices files changing by polling their modification times, which works the same
+// everywhere and is plenty for a handful of files
+pub struct FileWatcher {
+    files: Vec<WatchedFile>,
+    last_poll: Instant,
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl FileWatcher {
+    pub fn new<P: Into<PathBuf>>(paths: Vec<P>) -> FileWatcher {
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let path = path.into();
+                let modified = modified(&path);
+                WatchedFile { path, modified, pending: None }
+            })
+            .collect();
+
+        FileWatcher {
+            files,
+            last_poll: Instant::now(),
+        }
+    }
+
+    // the files that changed since the last call. a change is only reported once the
+    // modification time has held still for a poll, so half written files are skipped
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = Vec::new();
+        for file in self.files.iter_mut() {
+            let now = modified(&file.path);
+            if now == file.modified {
+                file.pending = None;
+            } else if now.is_some() && now == file.pending {
+                file.modified = now;
+                file.pending = None;
+                changed.push(file.path.clone());
+            } else {
+                // missing files are usually being replaced, wait for them to come back
+                file.pending = now;
+            }
+        }
+
+        changed
+    }
+}
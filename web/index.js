@@ -1,4 +1,4 @@
-import { WasmVM } from "chip8";
+import { WasmVM, wasm_memory } from "chip8";
 import test from "./roms.txt";
 import Alpine from 'alpinejs';
 
@@ -7,51 +7,70 @@ const initRom = roms[0];
 
 const audioCtx = new(window.AudioContext || window.webkitAudioContext)();
 
-let currentAnimation = undefined;
+let audioTime = 0; // when the next buffer of samples should start playing
+const AUDIO_LATENCY = 0.05;
 
-const cycle_loop = (vm) => {
-    const canvas = document.getElementById('canvas');
-    const ctx = canvas.getContext('2d');
-    ctx.clearRect(0, 0, canvas.width, canvas.height);
+// plays samples generated by the emulator back to back, so beeps are click-free and last as long as the sound timer
+const play_audio = (samples) => {
+    if(samples.length == 0) return;
 
-    // 600 fps
-    let redraw = false;
+    const buffer = audioCtx.createBuffer(1, samples.length, audioCtx.sampleRate);
+    buffer.copyToChannel(samples, 0);
+    const source = audioCtx.createBufferSource();
+    source.buffer = buffer;
+    source.connect(audioCtx.destination);
 
-    for(let i = 0; i < 10; i++) {
-        vm.emulate_cycle();
-        if(vm.should_redraw()) {
-            redraw = true;
-        }
+    // start over with a little latency after falling behind, e.g. while paused
+    if(audioTime < audioCtx.currentTime) {
+        audioTime = audioCtx.currentTime + AUDIO_LATENCY;
     }
+    source.start(audioTime);
+    audioTime += buffer.duration;
+}
 
-    // runs at 60fps
-    let beep = vm.decrement_timers();
-    if(beep) {
-        const oscillator = audioCtx.createOscillator();
-        oscillator.type = 'square'
-        oscillator.frequency.value = 356; 
-        const volume = audioCtx.createGain();
-        volume.connect(audioCtx.destination);
-        oscillator.connect(volume);
-        volume.gain.value = 0.02;
-        oscillator.start();
-        oscillator.stop(audioCtx.currentTime + 0.10);
-    }
+let currentAnimation = undefined;
+let currentVM = undefined;
+let speedMultiplier = 1;
+let currentPalette = 'auto';
+let currentFilter = 'off';
+let currentPostfx = 'off';
+let forceRedraw = false;
+
+// the display is rendered at its native 64x32 and scaled up when drawn to the page
+const screen = document.createElement('canvas');
+screen.width = 64;
+screen.height = 32;
+const screenCtx = screen.getContext('2d');
+
+const cycle_loop = (vm, timestamp, lastTimestamp) => {
+    const canvas = document.getElementById('canvas');
+    const ctx = canvas.getContext('2d');
 
-    if(redraw) {
-        const display = vm.get_display();
-        display.forEach((px, i) => {
-            const row = Math.floor(i / 64);
-            const col = i % 64;
-            if(px == 1) {
-                ctx.fillRect(col*10, row*10, 10, 10);
-            } 
-        });
+    // the emulator runs however many 60Hz frames are owed (none while paused)
+    const redraw = vm.update(lastTimestamp === undefined ? 0 : timestamp - lastTimestamp);
+
+    play_audio(vm.take_audio());
+
+    if(currentPostfx == 'off') {
+        const dirty = vm.refresh_rgba(forceRedraw);
+        if(dirty) {
+            // a view straight into wasm memory, made every time since growing memory detaches it
+            const rgba = new Uint8ClampedArray(wasm_memory().buffer, vm.rgba_ptr(), vm.rgba_len());
+            screenCtx.putImageData(new ImageData(rgba, 64, 32), 0, 0, dirty.x, dirty.y, dirty.width, dirty.height);
+            dirty.free();
+            ctx.imageSmoothingEnabled = false;
+            ctx.drawImage(screen, 0, 0, canvas.width, canvas.height);
+        }
+    } else if(redraw || forceRedraw) {
+        // effects work on the scaled picture, so the emulator renders at full size
+        const pixels = vm.render(canvas.width, canvas.height);
+        ctx.putImageData(new ImageData(pixels, canvas.width, canvas.height), 0, 0);
     }
+    forceRedraw = false;
 
 
-    currentAnimation = window.requestAnimationFrame(() => {
-        cycle_loop(vm);
+    currentAnimation = window.requestAnimationFrame((nextTimestamp) => {
+        cycle_loop(vm, nextTimestamp, timestamp);
     });
 }
 
@@ -69,28 +88,95 @@ const run = async (rom) => {
         currentAnimation = undefined;
     }
 
-    const vm = new WasmVM();
-    vm.load_program(rom);
+    // one machine for the whole page, reset with every ROM
+    if(!currentVM) {
+        currentVM = new WasmVM();
+        currentVM.set_speed(String(speedMultiplier));
+        currentVM.enable_audio(audioCtx.sampleRate);
+        currentVM.set_palette(currentPalette);
+        currentVM.set_filter(currentFilter);
+        currentVM.set_postfx(currentPostfx);
+    }
+    const vm = currentVM;
 
-    document.addEventListener("keydown", (e) => {
-        vm.set_key(e.code, true);
-    })
+    try {
+        vm.load_program(rom);
+    } catch(e) {
+        alert(`Can't load ROM: ${e}`);
+        return;
+    }
+    if(vm.rom_title()) {
+        console.log(`Loaded ${vm.rom_title()} by ${vm.rom_author()}`);
+    }
 
-    document.addEventListener("keyup", (e) => {
-        vm.set_key(e.code, false);
-    })
+    forceRedraw = true;
+    currentAnimation = window.requestAnimationFrame((timestamp) => {
+        cycle_loop(vm, timestamp, undefined);
+    });
+}
 
-    cycle_loop(vm);
+// emulation hotkeys, matching the desktop frontend
+const handle_hotkey = (vm, code) => {
+    switch(code) {
+        case "KeyP":
+            vm.toggle_pause();
+            return true;
+        case "Backspace":
+            vm.reset();
+            return true;
+        case "KeyN":
+            vm.advance_frame();
+            return true;
+        case "Tab":
+            vm.set_fast_forward(true);
+            return true;
+        case "Minus":
+        case "Equal":
+            speedMultiplier = code == "Minus" ? speedMultiplier / 2 : speedMultiplier * 2;
+            vm.set_speed(String(speedMultiplier));
+            return true;
+        case "BracketLeft":
+            vm.set_cycles_per_frame(vm.cycles_per_frame() - 1);
+            return true;
+        case "BracketRight":
+            vm.set_cycles_per_frame(vm.cycles_per_frame() + 1);
+            return true;
+        default:
+            return false;
+    }
 }
 
-document.addEventListener("alpine:init", async () => {
-    const canvas = document.getElementById('canvas');
-    const ctx = canvas.getContext('2d');
-    ctx.fillStyle = '#e0e0e0';
+// keyboard listeners are registered once and talk to whichever VM is running
+document.addEventListener("keydown", (e) => {
+    // browsers only allow audio to start after user input
+    if(audioCtx.state == "suspended") audioCtx.resume();
+    if(!currentVM) return;
+    if(handle_hotkey(currentVM, e.code)) {
+        e.preventDefault();
+        return;
+    }
+    currentVM.set_key(e.code, true);
+})
 
+document.addEventListener("keyup", (e) => {
+    if(!currentVM) return;
+    if(e.code == "Tab") {
+        currentVM.set_fast_forward(false);
+        return;
+    }
+    currentVM.set_key(e.code, false);
+})
+
+document.addEventListener("alpine:init", async () => {
     Alpine.data("chip8", () => ({
         roms,
         selectedRom: initRom,
+        palettes: ['auto', 'classic', 'amber', 'lcd', 'octo'],
+        selectedPalette: currentPalette,
+        filters: ['off', 'blend', 'phosphor', 'stable'],
+        selectedFilter: currentFilter,
+        postfxPresets: ['off', 'scanlines', 'crt', 'glow'],
+        selectedPostfx: currentPostfx,
         instructions: "",
 
         init() {
@@ -105,6 +191,30 @@ document.addEventListener("alpine:init", async () => {
             run(rom);
         },
 
+        setPalette(palette) {
+            currentPalette = palette;
+            if(currentVM) {
+                currentVM.set_palette(palette);
+                forceRedraw = true;
+            }
+        },
+
+        setFilter(filter) {
+            currentFilter = filter;
+            if(currentVM) {
+                currentVM.set_filter(filter);
+                forceRedraw = true;
+            }
+        },
+
+        setPostfx(preset) {
+            currentPostfx = preset;
+            if(currentVM) {
+                currentVM.set_postfx(preset);
+                forceRedraw = true;
+            }
+        },
+
         uploadRom(event) {
             if(!event.target.files.length) return;
             this.instructions = "";